@@ -0,0 +1,287 @@
+use crate::hash::*;
+use crate::mask::*;
+use crate::patchy::*;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+// Number of output regions that are patched in parallel before being written to a stream
+pub const STREAM_BATCH_REGIONS: usize = 16;
+
+// Amount of output between checkpoints of resumable patch application
+pub const CHECKPOINT_SIZE: u64 = 64 << 20;
+
+// Number of output bytes produced by each method
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ApplyStats {
+    // Shared with BASE file using reflinks
+    pub cloned: u64,
+    // Copied from BASE file by the kernel
+    pub copied: u64,
+    // Written from memory
+    pub written: u64,
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn clone_range(
+        source: &File,
+        source_offset: u64,
+        target: &File,
+        target_offset: u64,
+        size: u64,
+    ) -> std::io::Result<()> {
+        let range = libc::file_clone_range {
+            src_fd: source.as_raw_fd() as i64,
+            src_offset: source_offset,
+            src_length: size,
+            dest_offset: target_offset,
+        };
+        match unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONERANGE, &range) } {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    // Returns number of bytes that were copied before an error occurred, if any
+    pub fn copy_range(
+        source: &File,
+        source_offset: u64,
+        target: &File,
+        target_offset: u64,
+        size: u64,
+    ) -> (u64, std::io::Result<()>) {
+        let mut copied: u64 = 0;
+        while copied < size {
+            let mut source_pos = (source_offset + copied) as libc::off64_t;
+            let mut target_pos = (target_offset + copied) as libc::off64_t;
+            let result = unsafe {
+                libc::copy_file_range(
+                    source.as_raw_fd(),
+                    &mut source_pos,
+                    target.as_raw_fd(),
+                    &mut target_pos,
+                    (size - copied) as usize,
+                    0,
+                )
+            };
+            match result {
+                n if n > 0 => copied += n as u64,
+                0 => return (copied, Err(Error::other("Unexpected end of BASE file"))),
+                _ => return (copied, Err(Error::last_os_error())),
+            }
+        }
+        (copied, Ok(()))
+    }
+}
+
+fn write_at(mut file: &File, offset: u64, data: &[u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+// Copies BASE ranges into the output file using the fastest method that works.
+// Kernel methods are disabled after the first failure that is not specific to a range.
+struct RangeCopier<'a> {
+    base_file: &'a File,
+    base_data: &'a [u8],
+    output_file: &'a File,
+    clone_enabled: bool,
+    copy_enabled: bool,
+    clone_alignment: u64,
+    stats: ApplyStats,
+}
+
+impl<'a> RangeCopier<'a> {
+    fn new(base_file: &'a File, base_data: &'a [u8], output_file: &'a File) -> Self {
+        #[cfg(target_os = "linux")]
+        let clone_alignment = {
+            use std::os::unix::fs::MetadataExt;
+            output_file.metadata().map(|m| m.blksize()).unwrap_or(0)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let clone_alignment = 0;
+        Self {
+            base_file,
+            base_data,
+            output_file,
+            clone_enabled: cfg!(target_os = "linux") && clone_alignment != 0,
+            copy_enabled: cfg!(target_os = "linux"),
+            clone_alignment,
+            stats: ApplyStats::default(),
+        }
+    }
+
+    fn copy(&mut self, source: u64, target: u64, size: u64) -> std::io::Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            // Only whole file system blocks can be cloned, so the ends of the range are copied
+            let align = self.clone_alignment;
+            if self.clone_enabled && source % align == target % align {
+                let clone_begin = target.div_ceil(align) * align;
+                let clone_end = (target + size) / align * align;
+                if clone_begin < clone_end {
+                    let clone_source = source + (clone_begin - target);
+                    let clone_size = clone_end - clone_begin;
+                    match linux::clone_range(
+                        self.base_file,
+                        clone_source,
+                        self.output_file,
+                        clone_begin,
+                        clone_size,
+                    ) {
+                        Ok(()) => {
+                            self.stats.cloned += clone_size;
+                            self.copy(source, target, clone_begin - target)?;
+                            let tail = target + size - clone_end;
+                            return self.copy(clone_source + clone_size, clone_end, tail);
+                        }
+                        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                        Err(_) => self.clone_enabled = false,
+                    }
+                }
+            }
+            if self.copy_enabled {
+                let (copied, result) =
+                    linux::copy_range(self.base_file, source, self.output_file, target, size);
+                self.stats.copied += copied;
+                if result.is_ok() {
+                    return Ok(());
+                }
+                self.copy_enabled = false;
+                return self.copy(source + copied, target + copied, size - copied);
+            }
+        }
+        let begin = source as usize;
+        let end = begin + size as usize;
+        if end > self.base_data.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Copy command is out of BASE bounds",
+            ));
+        }
+        write_at(self.output_file, target, &self.base_data[begin..end])?;
+        self.stats.written += size;
+        Ok(())
+    }
+}
+
+// Applies patch directly to the output file, which must be empty. On Linux, BASE ranges are
+// cloned (reflinked) on file systems that support it or copied by the kernel, so only literal
+// data is written from memory, with buffered writes used as a fallback. Zero fill regions never
+// overlap copy targets and are left as holes. BASE file contents must match `base_data`.
+pub fn apply_patch_to_file(
+    base_file: &File,
+    base_data: &[u8],
+    patch: &Patch,
+    output_file: &File,
+) -> std::io::Result<ApplyStats> {
+    output_file.set_len(patch.other_size)?;
+    apply_patch_to_file_from(base_file, base_data, patch, output_file, 0, None)
+}
+
+pub fn patch_command_count(patch: &Patch) -> u64 {
+    (patch.base.len() + patch.other.len() + patch.fill.len()) as u64
+}
+
+// Same as apply_patch_to_file, but skips the first `start` commands (BASE copies, then literal
+// copies, then fills), which were already applied to the output file of `other_size` bytes.
+// Commands write fixed data to fixed ranges, so applying them again after a crash is harmless.
+// If `checkpoint` is provided, the output file is synced every CHECKPOINT_SIZE bytes and it is
+// called with the number of commands whose results are durable.
+pub fn apply_patch_to_file_from(
+    base_file: &File,
+    base_data: &[u8],
+    patch: &Patch,
+    output_file: &File,
+    start: u64,
+    mut checkpoint: Option<&mut dyn FnMut(u64) -> std::io::Result<()>>,
+) -> std::io::Result<ApplyStats> {
+    let mut copier = RangeCopier::new(base_file, base_data, output_file);
+    let base_count = patch.base.len();
+    let other_count = patch.other.len();
+    let command_count = patch_command_count(patch);
+    let mut checkpoint_size: u64 = 0;
+    for index in start..command_count {
+        let i = index as usize;
+        let size = if i < base_count {
+            let cmd = &patch.base[i];
+            copier.copy(cmd.source, cmd.target, cmd.size as u64)?;
+            cmd.size
+        } else if i < base_count + other_count {
+            let cmd = &patch.other[i - base_count];
+            let begin = cmd.source as usize;
+            let end = begin + cmd.size as usize;
+            write_at(output_file, cmd.target, &patch.data[begin..end])?;
+            copier.stats.written += cmd.size as u64;
+            cmd.size
+        } else {
+            let cmd = &patch.fill[i - base_count - other_count];
+            if cmd.byte != 0 {
+                write_at(output_file, cmd.target, &vec![cmd.byte; cmd.size as usize])?;
+                copier.stats.written += cmd.size as u64;
+            }
+            cmd.size
+        };
+        checkpoint_size += size as u64;
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            if checkpoint_size >= CHECKPOINT_SIZE && index + 1 < command_count {
+                output_file.sync_data()?;
+                checkpoint(index + 1)?;
+                checkpoint_size = 0;
+            }
+        }
+    }
+    Ok(copier.stats)
+}
+
+// Applies patch to a stream such as a pipe, which can't be seeked. Output is produced in order,
+// a batch of regions at a time, so memory use doesn't depend on output size. Masked regions
+// must lie within the output. Returns hash of the written output, which the caller has to check,
+// since the output can't be taken back if it turns out to be wrong.
+pub fn apply_patch_to_writer(
+    base_data: &[u8],
+    patch: &Patch,
+    masked: &[MaskedRegion],
+    writer: &mut dyn Write,
+) -> std::io::Result<Hash128> {
+    let regions = PatchRegions::new(patch);
+    let batch_size = (APPLY_REGION_SIZE * STREAM_BATCH_REGIONS) as u64;
+    let mut batch: Vec<u8> = Vec::new();
+    let mut hasher = blake3::Hasher::new();
+    let mut batch_begin: u64 = 0;
+    while batch_begin < patch.other_size {
+        let batch_end = std::cmp::min(batch_begin + batch_size, patch.other_size);
+        batch.clear();
+        batch.resize((batch_end - batch_begin) as usize, 0);
+        batch
+            .par_chunks_mut(APPLY_REGION_SIZE)
+            .enumerate()
+            .for_each(|(i, region)| {
+                let offset = batch_begin + (i * APPLY_REGION_SIZE) as u64;
+                regions.apply(base_data, region, offset);
+            });
+        for region in masked {
+            let begin = std::cmp::max(region.offset, batch_begin);
+            let end = std::cmp::min(region.offset + region.data.len() as u64, batch_end);
+            if begin < end {
+                let source = (begin - region.offset) as usize..(end - region.offset) as usize;
+                let target = (begin - batch_begin) as usize..(end - batch_begin) as usize;
+                batch[target].copy_from_slice(&region.data[source]);
+            }
+        }
+        hasher.update_with_join::<blake3::join::RayonJoin>(&batch);
+        writer.write_all(&batch)?;
+        batch_begin = batch_end;
+    }
+    writer.flush()?;
+    Ok(Hash128::new_from_blake3(&hasher.finalize()))
+}
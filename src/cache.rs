@@ -0,0 +1,123 @@
+use crate::hash::*;
+use crate::patchy::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE_CACHE_ID: [u8; 8] = *b"!patchs!";
+pub const SIGNATURE_CACHE_VERSION: u32 = 1;
+
+// Parameters that signature contents depend on, besides the input data itself
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct SignatureKey {
+    data_hash: Hash128,
+    data_size: u64,
+    block_size_bounds: (u64, u64),
+    density: u64,
+    align: u64,
+}
+
+impl SignatureKey {
+    fn new(data_hash: Hash128, data_size: usize, params: &DiffParams) -> Self {
+        Self {
+            data_hash,
+            data_size: data_size as u64,
+            block_size_bounds: (
+                params.block_size_bounds.0 as u64,
+                params.block_size_bounds.1 as u64,
+            ),
+            density: params.density as u64,
+            align: params.align as u64,
+        }
+    }
+    fn file_name(&self) -> String {
+        format!(
+            "{}-{}-{}-{}-{}.sig",
+            self.data_hash.to_hex_string(),
+            self.block_size_bounds.0,
+            self.block_size_bounds.1,
+            self.density,
+            self.align
+        )
+    }
+}
+
+// Stored in front of the signature, so that mismatching entries are rejected without reading it
+#[derive(Serialize, Deserialize)]
+struct SignatureCacheHeader {
+    id: [u8; 8],
+    version: u32,
+    key: SignatureKey,
+}
+
+// On-disk cache of OTHER signatures keyed by content hash and diff parameters, so that files
+// which are diffed repeatedly only have their blocks hashed once. Entries never go stale since
+// any change to the content or parameters produces a different key.
+pub struct SignatureCache {
+    dir: PathBuf,
+}
+
+impl SignatureCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn entry_path(&self, data_hash: Hash128, data_size: usize, params: &DiffParams) -> PathBuf {
+        let key = SignatureKey::new(data_hash, data_size, params);
+        self.dir.join(key.file_name())
+    }
+
+    // Returns None if there is no entry, or it is unreadable or was written by another version
+    pub fn load(
+        &self,
+        data_hash: Hash128,
+        data_size: usize,
+        params: &DiffParams,
+    ) -> Option<Signature> {
+        let key = SignatureKey::new(data_hash, data_size, params);
+        let file = File::open(self.dir.join(key.file_name())).ok()?;
+        let mut reader = BufReader::new(file);
+        let header: SignatureCacheHeader = bincode::deserialize_from(&mut reader).ok()?;
+        if header.id != SIGNATURE_CACHE_ID
+            || header.version != SIGNATURE_CACHE_VERSION
+            || header.key != key
+        {
+            return None;
+        }
+        bincode::deserialize_from(&mut reader).ok()
+    }
+
+    // Writes the entry to a temporary file first, so concurrent readers never see partial entries
+    pub fn store(
+        &self,
+        data_hash: Hash128,
+        data_size: usize,
+        params: &DiffParams,
+        signature: &Signature,
+    ) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let key = SignatureKey::new(data_hash, data_size, params);
+        let path = self.dir.join(key.file_name());
+        let temp_path = path.with_extension(format!("tmp{}", std::process::id()));
+        let header = SignatureCacheHeader {
+            id: SIGNATURE_CACHE_ID,
+            version: SIGNATURE_CACHE_VERSION,
+            key,
+        };
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            bincode::serialize_into(&mut writer, &header).map_err(Error::other)?;
+            bincode::serialize_into(&mut writer, signature).map_err(Error::other)?;
+            writer.flush()?;
+            std::fs::rename(&temp_path, &path)
+        };
+        let result = write();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+}
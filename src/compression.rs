@@ -0,0 +1,92 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+// Magic followed by deflate method
+const GZIP_MAGIC: [u8; 3] = [0x1F, 0x8B, 0x08];
+const GZIP_HEADER_SIZE: usize = 10;
+// Flag bits that must be zero
+const GZIP_RESERVED_FLAGS: u8 = 0xE0;
+
+// Inputs are decompressed in memory, so their decompressed size is limited to this by default
+pub const DEFAULT_MAX_DECOMPRESSED_FILE_SIZE: u64 = 1 << 32;
+
+// Whole-file compression formats that are transparently handled for BASE and OTHER inputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileCompression {
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl FileCompression {
+    pub const NAMES: [&'static str; 3] = ["none", "zstd", "gzip"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(FileCompression::None),
+            "zstd" => Some(FileCompression::Zstd),
+            "gzip" => Some(FileCompression::Gzip),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileCompression::None => "none",
+            FileCompression::Zstd => "zstd",
+            FileCompression::Gzip => "gzip",
+        }
+    }
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&ZSTD_MAGIC) {
+            FileCompression::Zstd
+        } else if data.len() >= GZIP_HEADER_SIZE
+            && data.starts_with(&GZIP_MAGIC)
+            && data[3] & GZIP_RESERVED_FLAGS == 0
+        {
+            FileCompression::Gzip
+        } else {
+            FileCompression::None
+        }
+    }
+    pub fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.decompress_limited(data, DEFAULT_MAX_DECOMPRESSED_FILE_SIZE)
+    }
+    // Fails if the output is larger than `limit` bytes
+    pub fn decompress_limited(&self, data: &[u8], limit: u64) -> std::io::Result<Vec<u8>> {
+        match self {
+            FileCompression::None => Ok(data.to_vec()),
+            FileCompression::Zstd => read_limited(zstd::stream::Decoder::new(data)?, limit),
+            FileCompression::Gzip => read_limited(MultiGzDecoder::new(data), limit),
+        }
+    }
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            FileCompression::None => Ok(data.to_vec()),
+            FileCompression::Zstd => {
+                zstd::stream::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+            }
+            FileCompression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+fn read_limited(reader: impl Read, limit: u64) -> std::io::Result<Vec<u8>> {
+    let mut result: Vec<u8> = Vec::new();
+    reader
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut result)?;
+    if result.len() as u64 > limit {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Decompressed size exceeds {} bytes", limit),
+        ));
+    }
+    Ok(result)
+}
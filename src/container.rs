@@ -0,0 +1,712 @@
+use crate::filter::*;
+use crate::hash::*;
+use crate::mask::*;
+use crate::patchy::*;
+use crate::precomp::*;
+use crate::sparse::*;
+use crate::validate::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+
+pub const PATCH_FILE_ID: [u8; 8] = *b"!patchy!";
+pub const PATCH_FILE_VERSION: u32 = 13;
+
+// Window size used for zstd long distance matching, which is also the largest window
+// that zstd decoder accepts by default
+const ZSTD_LONG_WINDOW_LOG: u32 = 27;
+
+// Literal data chunk size of seekable patches
+pub const DATA_CHUNK_SIZE: usize = 1 << 24;
+
+// Training works best with many small samples, so larger inputs are split into pieces
+const DICTIONARY_SAMPLE_SIZE: usize = 128 * 1024;
+
+// Shared zstd dictionary that improves compression of many small similar patches
+pub struct Dictionary {
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> std::io::Result<Self> {
+        let id = zstd_safe::get_dict_id(&data)
+            .ok_or_else(|| invalid_data("Not a zstd dictionary".to_string()))?;
+        Ok(Self { id, data })
+    }
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> std::io::Result<Self> {
+        let samples: Vec<&[u8]> = samples
+            .iter()
+            .flat_map(|sample| sample.as_ref().chunks(DICTIONARY_SAMPLE_SIZE))
+            .collect();
+        Self::new(zstd::dict::from_samples(&samples, max_size)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Zstd,
+    // Fast decompression for slow devices, compression level is ignored
+    Lz4,
+    // Best compression ratio for slow links
+    Xz,
+}
+
+impl Codec {
+    pub const NAMES: [&'static str; 4] = ["none", "zstd", "lz4", "xz"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Codec::None),
+            "zstd" => Some(Codec::Zstd),
+            "lz4" => Some(Codec::Lz4),
+            "xz" => Some(Codec::Xz),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+            Codec::Xz => "xz",
+        }
+    }
+    pub fn level_bounds(&self) -> (i32, i32) {
+        match self {
+            Codec::None | Codec::Lz4 => (0, 0),
+            Codec::Zstd => (1, 22),
+            Codec::Xz => (0, 9),
+        }
+    }
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Codec::None | Codec::Lz4 => 0,
+            Codec::Zstd => 15,
+            Codec::Xz => 9,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectionCodec {
+    pub codec: Codec,
+    pub level: i32,
+    pub long_distance: bool,
+}
+
+impl SectionCodec {
+    pub fn new(codec: Codec, level: i32) -> Self {
+        Self {
+            codec,
+            level,
+            long_distance: false,
+        }
+    }
+    // Dictionary is only used by zstd codec
+    pub fn compress(
+        &self,
+        data: &[u8],
+        dictionary: Option<&Dictionary>,
+    ) -> std::io::Result<Vec<u8>> {
+        match self.codec {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd if self.long_distance => {
+                let mut context = zstd_safe::create_cctx();
+                if let Some(dictionary) = dictionary {
+                    zstd_safe::cctx_load_dictionary(&mut context, &dictionary.data)
+                        .map_err(zstd_error)?;
+                }
+                let parameters = [
+                    zstd_safe::CParameter::CompressionLevel(self.level),
+                    zstd_safe::CParameter::EnableLongDistanceMatching(true),
+                    zstd_safe::CParameter::WindowLog(ZSTD_LONG_WINDOW_LOG),
+                ];
+                for parameter in parameters.iter().cloned() {
+                    zstd_safe::cctx_set_parameter(&mut context, parameter).map_err(zstd_error)?;
+                }
+                let mut result: Vec<u8> = vec![0; zstd_safe::compress_bound(data.len())];
+                let size = zstd_safe::compress2(&mut context, &mut result[..], data)
+                    .map_err(zstd_error)?;
+                result.truncate(size);
+                Ok(result)
+            }
+            Codec::Zstd => match dictionary {
+                Some(dictionary) => {
+                    let mut encoder = zstd::stream::Encoder::with_dictionary(
+                        Vec::new(),
+                        self.level,
+                        &dictionary.data,
+                    )?;
+                    encoder.write_all(data)?;
+                    encoder.finish()
+                }
+                None => zstd::stream::encode_all(data, self.level),
+            },
+            Codec::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder.finish().map_err(Error::other)
+            }
+            Codec::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), self.level as u32);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+    pub fn decompress(
+        &self,
+        data: &[u8],
+        dictionary: Option<&Dictionary>,
+    ) -> std::io::Result<Vec<u8>> {
+        self.decompress_limited(data, dictionary, u64::MAX)
+    }
+    // Decompresses at most `limit` + 1 bytes, so that callers can detect data that exceeds the
+    // limit without decompressing all of it
+    pub fn decompress_limited(
+        &self,
+        data: &[u8],
+        dictionary: Option<&Dictionary>,
+        limit: u64,
+    ) -> std::io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self.codec {
+            Codec::None => Box::new(data),
+            Codec::Zstd => match dictionary {
+                Some(dictionary) => Box::new(zstd::stream::Decoder::with_dictionary(
+                    data,
+                    &dictionary.data,
+                )?),
+                None => Box::new(zstd::stream::Decoder::new(data)?),
+            },
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(data)),
+            Codec::Xz => Box::new(xz2::read::XzDecoder::new(data)),
+        };
+        let mut result: Vec<u8> = Vec::new();
+        decoder
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut result)?;
+        Ok(result)
+    }
+}
+
+fn zstd_error(code: usize) -> Error {
+    Error::other(zstd_safe::get_error_name(code))
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Codecs used for different parts of the patch file
+#[derive(Clone, Copy, Debug)]
+pub struct PatchCodecs {
+    pub header: SectionCodec,
+    pub commands: SectionCodec,
+    pub data: SectionCodec,
+    // Literal data is compressed in independent chunks of this size if set, so that parts of it
+    // can be decompressed without the rest. Otherwise it is compressed as a whole, which keeps
+    // matches between distant literals.
+    pub data_chunk_size: Option<usize>,
+}
+
+// Everything needed to verify inputs and to transform them before and after patching
+#[derive(Serialize, Deserialize)]
+pub struct PatchHeader {
+    pub base_hash: Hash128,
+    pub other_hash: Hash128,
+    pub decompress_inputs: bool,
+    pub base_mask: Vec<MaskRange>,
+    pub other_masked: Vec<MaskedRegion>,
+    // Holes of sparse OTHER file, which are zero filled by patch commands as well
+    pub other_holes: Vec<Hole>,
+    // Hashes of OTHER file regions, which verify partial reconstruction
+    pub other_regions: RegionHashes,
+    // Hashes of BASE blocks that commands copy from, which allow applying the patch to a
+    // modified BASE. Empty unless the patch is relocatable.
+    pub base_blocks: Vec<Block>,
+    pub filter: Filter,
+    pub base_streams: Vec<CompressedStream>,
+    pub other_streams: Vec<CompressedStream>,
+    // Block layout of OTHER between common prefix and suffix that was used for matching
+    pub block_layout: BlockLayout,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HeaderSection {
+    header: PatchHeader,
+    other_size: u64,
+    data_size: u64,
+    data_chunk_size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Section {
+    codec: SectionCodec,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PatchFile {
+    id: [u8; 8],
+    version: u32,
+    dict_id: Option<u32>,
+    header: Section,
+    base_commands: Section,
+    other_commands: Section,
+    // Independently compressed chunks of data chunk size, except the last one. Literal data of
+    // patches that are not seekable is a single chunk.
+    data: Vec<Section>,
+}
+
+// Leading fields of PatchFile, which can be read without decoding the sections
+#[derive(Deserialize)]
+struct PatchFilePrefix {
+    id: [u8; 8],
+    version: u32,
+    dict_id: Option<u32>,
+}
+
+pub struct SectionStats {
+    pub name: &'static str,
+    pub size: usize,
+    pub compressed_size: usize,
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> std::io::Result<u64> {
+    let mut result: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| invalid_data("Unexpected end of command stream".to_string()))?;
+        *input = rest;
+        result |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(invalid_data("Invalid variable length integer".to_string()))
+}
+
+fn read_size(input: &mut &[u8]) -> std::io::Result<u32> {
+    u32::try_from(read_varint(input)?)
+        .map_err(|_| invalid_data("Command size is too large".to_string()))
+}
+
+fn write_delta(output: &mut Vec<u8>, value: u64, prev: u64) {
+    let delta = value.wrapping_sub(prev) as i64;
+    write_varint(output, ((delta << 1) ^ (delta >> 63)) as u64);
+}
+
+fn read_delta(input: &mut &[u8], prev: u64) -> std::io::Result<u64> {
+    let zigzag = read_varint(input)?;
+    let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+    Ok(prev.wrapping_add(delta as u64))
+}
+
+// Commands are stored as variable length integers relative to the end of the previous
+// command, which makes offsets of sequential copies encode to a single byte.
+fn encode_copy_cmds(output: &mut Vec<u8>, cmds: &[CopyCmd]) {
+    write_varint(output, cmds.len() as u64);
+    let (mut prev_source, mut prev_target) = (0u64, 0u64);
+    for cmd in cmds {
+        write_delta(output, cmd.source, prev_source);
+        write_delta(output, cmd.target, prev_target);
+        write_varint(output, cmd.size as u64);
+        prev_source = cmd.source.wrapping_add(cmd.size as u64);
+        prev_target = cmd.target.wrapping_add(cmd.size as u64);
+    }
+}
+
+fn decode_copy_cmds(input: &mut &[u8]) -> std::io::Result<Vec<CopyCmd>> {
+    let count = read_varint(input)? as usize;
+    let mut result: Vec<CopyCmd> = Vec::with_capacity(count.min(input.len()));
+    let (mut prev_source, mut prev_target) = (0u64, 0u64);
+    for _ in 0..count {
+        let source = read_delta(input, prev_source)?;
+        let target = read_delta(input, prev_target)?;
+        let size = read_size(input)?;
+        prev_source = source.wrapping_add(size as u64);
+        prev_target = target.wrapping_add(size as u64);
+        result.push(CopyCmd {
+            source,
+            target,
+            size,
+        });
+    }
+    Ok(result)
+}
+
+fn encode_fill_cmds(output: &mut Vec<u8>, cmds: &[FillCmd]) {
+    write_varint(output, cmds.len() as u64);
+    let mut prev_target = 0u64;
+    for cmd in cmds {
+        write_delta(output, cmd.target, prev_target);
+        write_varint(output, cmd.size as u64);
+        output.push(cmd.byte);
+        prev_target = cmd.target.wrapping_add(cmd.size as u64);
+    }
+}
+
+fn decode_fill_cmds(input: &mut &[u8]) -> std::io::Result<Vec<FillCmd>> {
+    let count = read_varint(input)? as usize;
+    let mut result: Vec<FillCmd> = Vec::with_capacity(count.min(input.len()));
+    let mut prev_target = 0u64;
+    for _ in 0..count {
+        let target = read_delta(input, prev_target)?;
+        let size = read_size(input)?;
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| invalid_data("Unexpected end of command stream".to_string()))?;
+        *input = rest;
+        prev_target = target.wrapping_add(size as u64);
+        result.push(FillCmd { target, size, byte });
+    }
+    Ok(result)
+}
+
+fn make_section(
+    name: &'static str,
+    data: &[u8],
+    codec: SectionCodec,
+    dictionary: Option<&Dictionary>,
+    stats: &mut Vec<SectionStats>,
+) -> std::io::Result<Section> {
+    let compressed = codec.compress(data, dictionary)?;
+    stats.push(SectionStats {
+        name,
+        size: data.len(),
+        compressed_size: compressed.len(),
+    });
+    Ok(Section {
+        codec,
+        data: compressed,
+    })
+}
+
+pub fn serialize_patch(
+    header: PatchHeader,
+    patch: &Patch,
+    codecs: &PatchCodecs,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<(Vec<u8>, Vec<SectionStats>)> {
+    let mut stats: Vec<SectionStats> = Vec::new();
+    let data_chunk_size = codecs.data_chunk_size.unwrap_or(patch.data.len()).max(1);
+    let header_section = HeaderSection {
+        header,
+        other_size: patch.other_size,
+        data_size: patch.data.len() as u64,
+        data_chunk_size: data_chunk_size as u64,
+    };
+    let header_serialized = bincode::serialize(&header_section)
+        .map_err(|e| invalid_data(format!("Could not serialize patch header: {}", e)))?;
+    let mut base_commands: Vec<u8> = Vec::new();
+    encode_copy_cmds(&mut base_commands, &patch.base);
+    let mut other_commands: Vec<u8> = Vec::new();
+    encode_copy_cmds(&mut other_commands, &patch.other);
+    encode_fill_cmds(&mut other_commands, &patch.fill);
+    let data_chunks = patch
+        .data
+        .par_chunks(data_chunk_size)
+        .map(|chunk| {
+            Ok(Section {
+                codec: codecs.data,
+                data: codecs.data.compress(chunk, dictionary)?,
+            })
+        })
+        .collect::<std::io::Result<Vec<Section>>>()?;
+    stats.push(SectionStats {
+        name: "data",
+        size: patch.data.len(),
+        compressed_size: data_chunks.iter().map(|chunk| chunk.data.len()).sum(),
+    });
+    let mut make = |name: &'static str, data: &[u8], codec: SectionCodec| {
+        make_section(name, data, codec, dictionary, &mut stats)
+    };
+    let patch_file = PatchFile {
+        id: PATCH_FILE_ID,
+        version: PATCH_FILE_VERSION,
+        dict_id: dictionary.map(|dictionary| dictionary.id),
+        header: make("header", &header_serialized, codecs.header)?,
+        base_commands: make("base commands", &base_commands, codecs.commands)?,
+        other_commands: make("other commands", &other_commands, codecs.commands)?,
+        data: data_chunks,
+    };
+    // Data is compressed first, but listed last like in the file
+    stats.rotate_left(1);
+    let result = bincode::serialize(&patch_file)
+        .map_err(|e| invalid_data(format!("Could not serialize patch file: {}", e)))?;
+    Ok((result, stats))
+}
+
+fn check_patch_file_id(id: [u8; 8], version: u32) -> std::io::Result<()> {
+    if id != PATCH_FILE_ID || version != PATCH_FILE_VERSION {
+        return Err(invalid_data(format!(
+            "Patch header is [{:?} v{}] but expected to be [{:?} v{}]",
+            id, version, PATCH_FILE_ID, PATCH_FILE_VERSION
+        )));
+    }
+    Ok(())
+}
+
+// Returns ID of the dictionary that is required to decompress the patch
+pub fn patch_dictionary_id(data: &[u8]) -> std::io::Result<Option<u32>> {
+    let prefix: PatchFilePrefix = bincode::deserialize(data)
+        .map_err(|e| invalid_data(format!("Could not deserialize patch file: {}", e)))?;
+    check_patch_file_id(prefix.id, prefix.version)?;
+    Ok(prefix.dict_id)
+}
+
+fn read_patch_file(data: &[u8], dictionary: Option<&Dictionary>) -> std::io::Result<PatchFile> {
+    let patch_file: PatchFile = bincode::deserialize(data)
+        .map_err(|e| invalid_data(format!("Could not deserialize patch file: {}", e)))?;
+    check_patch_file_id(patch_file.id, patch_file.version)?;
+    let dict_id = dictionary.map(|dictionary| dictionary.id);
+    if patch_file.dict_id != dict_id {
+        return Err(invalid_data(match patch_file.dict_id {
+            Some(id) => format!("Patch requires dictionary {:08x}", id),
+            None => "Patch does not use a dictionary".to_string(),
+        }));
+    }
+    Ok(patch_file)
+}
+
+fn decompress_section(
+    name: &'static str,
+    section: &Section,
+    dictionary: Option<&Dictionary>,
+    limit: u64,
+) -> std::io::Result<Vec<u8>> {
+    let result = section
+        .codec
+        .decompress_limited(&section.data, dictionary, limit)?;
+    match result.len() as u64 > limit {
+        true => Err(Error::from(PatchError::SectionTooLarge {
+            section: name,
+            limit,
+        })),
+        false => Ok(result),
+    }
+}
+
+// Decompresses a data chunk, which must have the size implied by its position
+fn decompress_chunk(
+    chunk: &Section,
+    dictionary: Option<&Dictionary>,
+    expected_size: usize,
+) -> std::io::Result<Vec<u8>> {
+    let result = decompress_section("data", chunk, dictionary, expected_size as u64)?;
+    if result.len() != expected_size {
+        return Err(invalid_data(format!(
+            "Patch data chunk size is {} but expected to be {}",
+            result.len(),
+            expected_size
+        )));
+    }
+    Ok(result)
+}
+
+// Returns decompressed contents of header, base commands, other commands and data sections
+fn decompress_sections(
+    data: &[u8],
+    dictionary: Option<&Dictionary>,
+    limits: &PatchLimits,
+) -> std::io::Result<[Vec<u8>; 4]> {
+    let patch_file = read_patch_file(data, dictionary)?;
+    let limit = limits.max_section_size;
+    let decompress = |name: &'static str, section: &Section| {
+        decompress_section(name, section, dictionary, limit)
+    };
+    // Chunk sizes are in the header, so only the total size is checked here
+    let mut data: Vec<u8> = Vec::new();
+    for chunk in &patch_file.data {
+        let chunk = decompress_section("data", chunk, dictionary, limit - data.len() as u64)?;
+        if (data.len() + chunk.len()) as u64 > limit {
+            return Err(Error::from(PatchError::SectionTooLarge {
+                section: "data",
+                limit,
+            }));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok([
+        decompress("header", &patch_file.header)?,
+        decompress("base commands", &patch_file.base_commands)?,
+        decompress("other commands", &patch_file.other_commands)?,
+        data,
+    ])
+}
+
+// Decompressed command and data sections, which are used as dictionary training samples
+pub fn patch_training_samples(
+    data: &[u8],
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<Vec<Vec<u8>>> {
+    let [_, base_commands, other_commands, data] =
+        decompress_sections(data, dictionary, &PatchLimits::default())?;
+    Ok(vec![base_commands, other_commands, data])
+}
+
+pub fn deserialize_patch(
+    data: &[u8],
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<(PatchHeader, Patch)> {
+    deserialize_patch_with_limits(data, dictionary, &PatchLimits::default())
+}
+
+// Section size limit is enforced while decompressing, the patch itself is not validated
+pub fn deserialize_patch_with_limits(
+    data: &[u8],
+    dictionary: Option<&Dictionary>,
+    limits: &PatchLimits,
+) -> std::io::Result<(PatchHeader, Patch)> {
+    let [header_serialized, base_commands, other_commands, data] =
+        decompress_sections(data, dictionary, limits)?;
+    let header_section: HeaderSection = bincode::deserialize(&header_serialized)
+        .map_err(|e| invalid_data(format!("Could not deserialize patch header: {}", e)))?;
+    if data.len() as u64 != header_section.data_size {
+        return Err(invalid_data(format!(
+            "Patch data size is {} but expected to be {}",
+            data.len(),
+            header_section.data_size
+        )));
+    }
+    let patch = decode_patch_commands(&header_section, &base_commands, &other_commands, data)?;
+    Ok((header_section.header, patch))
+}
+
+fn decode_patch_commands(
+    header_section: &HeaderSection,
+    mut base_commands: &[u8],
+    mut other_commands: &[u8],
+    data: Vec<u8>,
+) -> std::io::Result<Patch> {
+    Ok(Patch {
+        data,
+        base: decode_copy_cmds(&mut base_commands)?,
+        other: decode_copy_cmds(&mut other_commands)?,
+        fill: decode_fill_cmds(&mut other_commands)?,
+        other_size: header_section.other_size,
+    })
+}
+
+// Literal data of a patch file that is decompressed one chunk at a time when it is needed
+pub struct PatchData<'a> {
+    chunks: Vec<Section>,
+    chunk_size: u64,
+    size: u64,
+    dictionary: Option<&'a Dictionary>,
+}
+
+impl PatchData<'_> {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    // Decompresses only the chunks that literal copies of the patch refer to and makes the copies
+    // refer to the decompressed data, which becomes patch data
+    pub fn load_literals(&self, patch: &mut Patch) -> std::io::Result<()> {
+        let chunk_size = self.chunk_size;
+        // Offsets of needed chunks in the decompressed data
+        let mut chunk_offsets: BTreeMap<usize, u64> = BTreeMap::new();
+        for (index, cmd) in patch.other.iter().enumerate() {
+            let end = cmd
+                .source
+                .checked_add(cmd.size as u64)
+                .filter(|&end| end <= self.size)
+                .ok_or(PatchError::SourceOutOfBounds {
+                    list: CommandList::Other,
+                    index,
+                })?;
+            if cmd.size != 0 {
+                for index in cmd.source / chunk_size..end.div_ceil(chunk_size) {
+                    chunk_offsets.insert(index as usize, 0);
+                }
+            }
+        }
+        let chunks = chunk_offsets
+            .keys()
+            .collect::<Vec<&usize>>()
+            .par_iter()
+            .map(|&&index| {
+                let missing = || invalid_data("Patch data chunk is missing".to_string());
+                let chunk = self.chunks.get(index).ok_or_else(missing)?;
+                let remaining = (index as u64)
+                    .checked_mul(chunk_size)
+                    .and_then(|begin| self.size.checked_sub(begin))
+                    .ok_or_else(missing)?;
+                let expected_size = min(chunk_size, remaining);
+                decompress_chunk(chunk, self.dictionary, expected_size as usize)
+            })
+            .collect::<std::io::Result<Vec<Vec<u8>>>>()?;
+        let mut data: Vec<u8> = Vec::new();
+        for ((_, offset), chunk) in chunk_offsets.iter_mut().zip(chunks) {
+            *offset = data.len() as u64;
+            data.extend_from_slice(&chunk);
+        }
+        // Chunks that a command spans are consecutive, so they are contiguous in data as well
+        for cmd in patch.other.iter_mut() {
+            if cmd.size != 0 {
+                let index = (cmd.source / chunk_size) as usize;
+                cmd.source = chunk_offsets[&index] + cmd.source % chunk_size;
+            }
+        }
+        patch.data = data;
+        Ok(())
+    }
+}
+
+// Same as deserialize_patch_with_limits, but literal data is left compressed and returned
+// separately, so that patch data is empty
+pub fn deserialize_patch_seekable<'a>(
+    data: &[u8],
+    dictionary: Option<&'a Dictionary>,
+    limits: &PatchLimits,
+) -> std::io::Result<(PatchHeader, Patch, PatchData<'a>)> {
+    let patch_file = read_patch_file(data, dictionary)?;
+    let limit = limits.max_section_size;
+    let decompress = |name: &'static str, section: &Section| {
+        decompress_section(name, section, dictionary, limit)
+    };
+    let header_serialized = decompress("header", &patch_file.header)?;
+    let header_section: HeaderSection = bincode::deserialize(&header_serialized)
+        .map_err(|e| invalid_data(format!("Could not deserialize patch header: {}", e)))?;
+    let data_size = header_section.data_size;
+    if data_size > limit {
+        return Err(Error::from(PatchError::SectionTooLarge {
+            section: "data",
+            limit,
+        }));
+    }
+    let chunk_size = header_section.data_chunk_size;
+    if chunk_size == 0 || patch_file.data.len() as u64 != data_size.div_ceil(chunk_size) {
+        return Err(invalid_data(
+            "Patch data chunk count is invalid".to_string(),
+        ));
+    }
+    let patch = decode_patch_commands(
+        &header_section,
+        &decompress("base commands", &patch_file.base_commands)?,
+        &decompress("other commands", &patch_file.other_commands)?,
+        Vec::new(),
+    )?;
+    let patch_data = PatchData {
+        chunks: patch_file.data,
+        chunk_size,
+        size: data_size,
+        dictionary,
+    };
+    Ok((header_section.header, patch, patch_data))
+}
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::min;
+use std::convert::TryInto;
+
+// Reversible transforms applied to BASE and OTHER before diffing and undone after patching.
+// Filters must be exact inverses of each other and must produce the same set of transformed
+// regions when run on either the original or the transformed data.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    None,
+    X86,
+}
+
+impl Filter {
+    pub const NAMES: [&'static str; 2] = ["none", "x86"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Filter::None),
+            "x86" => Some(Filter::X86),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::None => "none",
+            Filter::X86 => "x86",
+        }
+    }
+    pub fn encode<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Filter::None => Cow::Borrowed(data),
+            Filter::X86 => {
+                let mut result = data.to_vec();
+                for (begin, end) in find_code_regions(&result) {
+                    x86_convert(&mut result[begin..end], begin as u32, true);
+                }
+                Cow::Owned(result)
+            }
+        }
+    }
+    pub fn decode(&self, data: &mut [u8]) {
+        match self {
+            Filter::None => {}
+            Filter::X86 => {
+                for (begin, end) in find_code_regions(data) {
+                    x86_convert(&mut data[begin..end], begin as u32, false);
+                }
+            }
+        }
+    }
+}
+
+const ELF_HEADER_SIZE: usize = 64;
+const ELF_SECTION_HEADER_SIZE: usize = 64;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+const ELF_SECTION_NOBITS: u32 = 8;
+const ELF_SECTION_FLAG_EXECINSTR: u64 = 0x4;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// Returns file ranges of executable sections of a little-endian x86-64 ELF file or None if the
+// data doesn't look like one.
+fn find_elf_code_sections(data: &[u8]) -> Option<Vec<(usize, usize)>> {
+    if data.len() < ELF_HEADER_SIZE
+        || data[0..4] != *b"\x7fELF"
+        || data[4] != 2 // 64 bit
+        || data[5] != 1 // little endian
+        || read_u16(data, 0x12) != ELF_MACHINE_X86_64
+    {
+        return None;
+    }
+    let section_table_offset = read_u64(data, 0x28) as usize;
+    let section_header_size = read_u16(data, 0x3A) as usize;
+    let section_count = read_u16(data, 0x3C) as usize;
+    if section_header_size != ELF_SECTION_HEADER_SIZE {
+        return None;
+    }
+    let section_table_end =
+        section_table_offset.checked_add(section_count * section_header_size)?;
+    if section_table_end > data.len() {
+        return None;
+    }
+    let mut result: Vec<(usize, usize)> = Vec::new();
+    for i in 0..section_count {
+        let header = section_table_offset + i * section_header_size;
+        let section_type = read_u32(data, header + 0x04);
+        let section_flags = read_u64(data, header + 0x08);
+        let section_offset = read_u64(data, header + 0x18) as usize;
+        let section_size = read_u64(data, header + 0x20) as usize;
+        if section_type == ELF_SECTION_NOBITS || section_flags & ELF_SECTION_FLAG_EXECINSTR == 0 {
+            continue;
+        }
+        let begin = min(section_offset, data.len());
+        let end = min(section_offset.saturating_add(section_size), data.len());
+        // Headers that are used to find the sections are never transformed,
+        // which guarantees that decoding finds exactly the same regions.
+        for (begin, end) in subtract_range((begin, end), (0, ELF_HEADER_SIZE)) {
+            for range in subtract_range((begin, end), (section_table_offset, section_table_end)) {
+                result.push(range);
+            }
+        }
+    }
+    // Overlapping sections must only be transformed once
+    result.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(result.len());
+    for range in result {
+        match merged.last_mut() {
+            Some(last) if range.0 <= last.1 => last.1 = last.1.max(range.1),
+            _ => merged.push(range),
+        }
+    }
+    Some(merged)
+}
+
+fn subtract_range(range: (usize, usize), hole: (usize, usize)) -> Vec<(usize, usize)> {
+    let mut result: Vec<(usize, usize)> = Vec::new();
+    if range.0 < min(range.1, hole.0) {
+        result.push((range.0, min(range.1, hole.0)));
+    }
+    if range.0.max(hole.1) < range.1 {
+        result.push((range.0.max(hole.1), range.1));
+    }
+    result
+}
+
+// Executable sections of ELF files or the whole file for anything else
+fn find_code_regions(data: &[u8]) -> Vec<(usize, usize)> {
+    match find_elf_code_sections(data) {
+        Some(sections) => sections,
+        None => vec![(0, data.len())],
+    }
+}
+
+// Converts relative CALL (E8) and JMP (E9) displacements to absolute offsets when encoding and
+// back when decoding. Only displacements within +/-16MB are converted and the converted value is
+// kept in the same range, so that the decoder sees the same set of candidates as the encoder.
+fn x86_convert(data: &mut [u8], base_offset: u32, encode: bool) {
+    const INSTRUCTION_SIZE: usize = 5;
+    if data.len() < INSTRUCTION_SIZE {
+        return;
+    }
+    let mut pos: usize = 0;
+    while pos <= data.len() - INSTRUCTION_SIZE {
+        if data[pos] != 0xE8 && data[pos] != 0xE9 {
+            pos += 1;
+            continue;
+        }
+        let high_byte = data[pos + 4];
+        if high_byte == 0x00 || high_byte == 0xFF {
+            let value = read_u32(data, pos + 1);
+            let next_offset = base_offset.wrapping_add((pos + INSTRUCTION_SIZE) as u32);
+            let converted = if encode {
+                value.wrapping_add(next_offset)
+            } else {
+                value.wrapping_sub(next_offset)
+            };
+            // Sign-extend from bit 24
+            let converted = ((converted << 7) as i32 >> 7) as u32;
+            data[pos + 1..pos + INSTRUCTION_SIZE].copy_from_slice(&converted.to_le_bytes());
+        }
+        pos += INSTRUCTION_SIZE;
+    }
+}
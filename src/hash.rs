@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use core::fmt;
+use rayon::prelude::*;
 
 pub struct RollingHash {
     a: u16,
@@ -7,6 +8,12 @@ pub struct RollingHash {
     count: usize,
 }
 
+impl Default for RollingHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RollingHash {
     pub fn new() -> Self {
         RollingHash {
@@ -77,8 +84,56 @@ pub fn compute_hash_strong(input: &[u8]) -> Hash128 {
     Hash128::new_from_blake3(&hasher_blake3.finalize())
 }
 
+// Same as compute_hash_strong, but uses multiple threads, which only pays off for large inputs
+// such as whole files
+pub fn compute_hash_strong_parallel(input: &[u8]) -> Hash128 {
+    let mut hasher_blake3 = blake3::Hasher::new();
+    hasher_blake3.update_with_join::<blake3::join::RayonJoin>(input);
+    Hash128::new_from_blake3(&hasher_blake3.finalize())
+}
+
 pub fn compute_hash_weak(input: &[u8]) -> u32 {
     let mut hash_rolling = RollingHash::new();
-    hash_rolling.update(&input);
+    hash_rolling.update(input);
     hash_rolling.get()
 }
+
+// Hashes of consecutive input regions, which can verify a part of the input without the rest.
+// Empty if the input was not split into regions.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RegionHashes {
+    pub region_size: u64,
+    pub hashes: Vec<Hash128>,
+}
+
+impl RegionHashes {
+    pub fn new(input: &[u8], region_size: u64) -> Self {
+        Self {
+            region_size,
+            hashes: input
+                .par_chunks(region_size as usize)
+                .map(compute_hash_strong)
+                .collect(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+    // Returns the smallest range of whole regions that contains range [begin, end)
+    pub fn covering_range(&self, begin: u64, end: u64, input_size: u64) -> (u64, u64) {
+        let region_begin = begin / self.region_size * self.region_size;
+        let region_end = end.next_multiple_of(self.region_size).min(input_size);
+        (region_begin, region_end)
+    }
+    // Checks whole regions of the input that start at `offset`, returns offset of the first
+    // region that doesn't match
+    pub fn verify(&self, data: &[u8], offset: u64) -> Result<(), u64> {
+        let first = (offset / self.region_size) as usize;
+        for (i, region) in data.chunks(self.region_size as usize).enumerate() {
+            if self.hashes.get(first + i) != Some(&compute_hash_strong(region)) {
+                return Err(offset + i as u64 * self.region_size);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use crate::hash::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+pub const JOURNAL_FILE_ID: [u8; 8] = *b"!patchj!";
+pub const JOURNAL_FILE_VERSION: u32 = 2;
+
+// Identifies the patch application that the journal belongs to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JournalHeader {
+    pub patch_hash: Hash128,
+    pub other_hash: Hash128,
+    pub other_size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalFilePrefix {
+    id: [u8; 8],
+    version: u32,
+    header: JournalHeader,
+    // Name of the temporary output file, which is in the same directory as the journal
+    temp_name: OsString,
+}
+
+// Progress of patch application, stored next to the output file. The journal is a fixed size
+// header followed by records with the number of completed patch commands, each appended and
+// synced after the output file. Torn records at the end are ignored.
+pub struct ApplyJournal {
+    file: File,
+    path: PathBuf,
+    temp_path: PathBuf,
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl ApplyJournal {
+    pub fn path_for(output_path: &Path) -> PathBuf {
+        let mut path = output_path.as_os_str().to_os_string();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    pub fn create(path: &Path, header: JournalHeader, temp_path: &Path) -> std::io::Result<Self> {
+        let temp_name = temp_path
+            .file_name()
+            .ok_or_else(|| invalid_data("Invalid temporary file name"))?;
+        let mut file = File::create(path)?;
+        let prefix = JournalFilePrefix {
+            id: JOURNAL_FILE_ID,
+            version: JOURNAL_FILE_VERSION,
+            header,
+            temp_name: temp_name.to_os_string(),
+        };
+        bincode::serialize_into(&mut file, &prefix).map_err(Error::other)?;
+        file.sync_all()?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            temp_path: path.with_file_name(temp_name),
+        })
+    }
+
+    // Returns the journal and the number of completed commands,
+    // or an error if the journal belongs to another patch application
+    pub fn open(path: &Path, header: JournalHeader) -> std::io::Result<(Self, u64)> {
+        let mut data: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let prefix: JournalFilePrefix =
+            bincode::deserialize(&data).map_err(|_| invalid_data("Could not read journal file"))?;
+        if prefix.id != JOURNAL_FILE_ID || prefix.version != JOURNAL_FILE_VERSION {
+            return Err(invalid_data("Unsupported journal file"));
+        }
+        if prefix.header != header {
+            return Err(invalid_data("Journal belongs to a different patch"));
+        }
+        if Path::new(&prefix.temp_name).file_name() != Some(prefix.temp_name.as_os_str()) {
+            return Err(invalid_data("Invalid temporary file name"));
+        }
+        let prefix_size = bincode::serialized_size(&prefix).map_err(Error::other)? as usize;
+        let records = &data[prefix_size..];
+        let record_count = records.len() / 8;
+        let completed = match record_count {
+            0 => 0,
+            _ => {
+                let last = &records[(record_count - 1) * 8..record_count * 8];
+                u64::from_le_bytes(last.try_into().unwrap())
+            }
+        };
+        // Torn record is dropped, so that new records are aligned
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len((prefix_size + record_count * 8) as u64)?;
+        let mut journal = Self {
+            file,
+            path: path.to_path_buf(),
+            temp_path: path.with_file_name(&prefix.temp_name),
+        };
+        journal.file.seek(std::io::SeekFrom::End(0))?;
+        Ok((journal, completed))
+    }
+
+    // Temporary output file that the journal records progress of
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    pub fn record(&mut self, completed: u64) -> std::io::Result<()> {
+        self.file.write_all(&completed.to_le_bytes())?;
+        self.file.sync_data()
+    }
+
+    pub fn remove(self) -> std::io::Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)
+    }
+}
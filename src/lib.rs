@@ -4,5 +4,41 @@ pub use self::patchy::*;
 pub mod hash;
 pub use self::hash::*;
 
+pub mod mask;
+pub use self::mask::*;
+
+pub mod compression;
+pub use self::compression::*;
+
+pub mod container;
+pub use self::container::*;
+
+pub mod filter;
+pub use self::filter::*;
+
+pub mod precomp;
+pub use self::precomp::*;
+
+pub mod cache;
+pub use self::cache::*;
+
+pub mod apply;
+pub use self::apply::*;
+
+pub mod journal;
+pub use self::journal::*;
+
+pub mod output;
+pub use self::output::*;
+
+pub mod validate;
+pub use self::validate::*;
+
+pub mod sparse;
+pub use self::sparse::*;
+
+pub mod relocate;
+pub use self::relocate::*;
+
 #[cfg(test)]
 mod test;
@@ -1,77 +1,242 @@
 use anyhow::{anyhow, Context, Result};
 use clap::{App, AppSettings, Arg, SubCommand};
 use memmap::MmapOptions;
+use patchy::apply::*;
+use patchy::cache::*;
+use patchy::compression::*;
+use patchy::container::*;
+use patchy::filter::*;
 use patchy::hash::*;
+use patchy::journal::*;
+use patchy::mask::*;
+use patchy::output::*;
 use patchy::patchy::*;
-use serde::{Deserialize, Serialize};
+use patchy::precomp::*;
+use patchy::relocate::*;
+use patchy::sparse::*;
+use patchy::validate::*;
+use rayon::prelude::*;
+use std::borrow::Cow;
 use std::cmp::{max, min};
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
 
 const BLOCK_SIZE_BOUNDS_LOG2: (i32, i32) = (6, 24);
 const DEFAULT_BLOCK_SIZE_LOG2: i32 = 11; // experimentally found to be the best value for smallest patch size
 
-const COMPRESSION_LEVEL_BOUNDS: (i32, i32) = (1, 22);
-const DEFAULT_COMPRESSION_LEVEL: i32 = 15;
+const ALIGN_BOUNDS_LOG2: (i32, i32) = (0, 24);
 
-fn size_mb(size: usize) -> f64 {
-    let mb = (1 << 20) as f64;
-    (size as f64) / mb
-}
+const DENSITY_BOUNDS: (i32, i32) = (1, 16);
+
+const COMMANDS_COMPRESSION_LEVEL: i32 = 19;
+
+const DEFAULT_DICTIONARY_SIZE: usize = 112640; // same as zstd command line tool
 
-fn compress(data: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
-    zstd::stream::encode_all(data, level)
+const REGION_HASH_SIZE: u64 = 1 << 22; // granularity of partial reconstruction
+
+const RELOCATION_BLOCK_SIZE: usize = 1 << 14; // granularity of BASE data relocation
+
+// File name that refers to stdin for inputs and stdout for outputs
+const STDIO_FILENAME: &str = "-";
+
+fn is_stdio(filename: &str) -> bool {
+    filename == STDIO_FILENAME
 }
 
-fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
-    zstd::stream::decode_all(data)
+// Set when data is written to stdout, status messages then go to stderr
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STDOUT_IS_DATA.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
 }
 
-const PATCH_FILE_ID: [u8; 8] = *b"!patchy!";
-const PATCH_FILE_VERSION: u32 = 1;
-#[derive(Serialize, Deserialize)]
-struct PatchWithHeader {
-    id: [u8; 8],
-    version: u32,
-    base_hash: Hash128,
-    other_hash: Hash128,
-    patch: Patch,
+fn size_mb(size: usize) -> f64 {
+    let mb = (1 << 20) as f64;
+    (size as f64) / mb
 }
 
 struct MappedFileIn {
     mmap: Option<memmap::Mmap>,
+    // Contents of stdin, which can't be mapped
+    piped: Option<Vec<u8>>,
 }
 
 impl Deref for MappedFileIn {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
-        match &self.mmap {
-            Some(mmap) => &mmap,
-            None => &[],
+        match (&self.mmap, &self.piped) {
+            (Some(mmap), _) => mmap,
+            (None, Some(piped)) => piped,
+            (None, None) => &[],
         }
     }
 }
 
 fn mmap_file_in(filename: &str) -> Result<MappedFileIn> {
+    if is_stdio(filename) {
+        let mut piped: Vec<u8> = Vec::new();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut piped)
+            .context("Can't read input from stdin")?;
+        return Ok(MappedFileIn {
+            mmap: None,
+            piped: Some(piped),
+        });
+    }
     let file = File::open(filename).context("Can't open input file")?;
     match file.metadata()?.len() {
-        0 => Ok(MappedFileIn { mmap: None }),
+        0 => Ok(MappedFileIn {
+            mmap: None,
+            piped: None,
+        }),
         _ => {
             let mmap = unsafe {
                 MmapOptions::new()
                     .map(&file)
                     .context("Can't memory map input file")?
             };
-            Ok(MappedFileIn { mmap: Some(mmap) })
+            Ok(MappedFileIn {
+                mmap: Some(mmap),
+                piped: None,
+            })
         }
     }
 }
 
+// Input file contents, transparently decompressed if the file is compressed
+struct FileIn {
+    mapped: MappedFileIn,
+    decompressed: Option<Vec<u8>>,
+}
+
+impl Deref for FileIn {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match &self.decompressed {
+            Some(decompressed) => decompressed,
+            None => &self.mapped,
+        }
+    }
+}
+
+// Inputs that look compressed must decompress within the size limit, raw data is only used
+// when decompression is disabled
+fn read_file_in(filename: &str, decompress: bool, max_decompressed_size: u64) -> Result<FileIn> {
+    let mapped = mmap_file_in(filename)?;
+    let compression = match decompress {
+        true => FileCompression::detect(&mapped),
+        false => FileCompression::None,
+    };
+    let decompressed = match compression {
+        FileCompression::None => None,
+        _ => {
+            status!("Decompressing '{}' ({})", filename, compression.name());
+            let decompressed = compression
+                .decompress_limited(&mapped, max_decompressed_size)
+                .with_context(|| {
+                    format!(
+                        "Could not decompress '{}', diff with -r to use compressed inputs as raw data",
+                        filename
+                    )
+                })?;
+            Some(decompressed)
+        }
+    };
+    Ok(FileIn {
+        mapped,
+        decompressed,
+    })
+}
+
+fn load_dictionary(filename: &str) -> Result<Dictionary> {
+    let data = std::fs::read(filename).context("Can't read dictionary file")?;
+    Dictionary::new(data).context("Can't load dictionary")
+}
+
+// Dictionary path may be a file or a directory that contains the dictionary with a given ID
+fn find_dictionary(path: &str, id: u32) -> Result<Dictionary> {
+    if !std::path::Path::new(path).is_dir() {
+        let dictionary = load_dictionary(path)?;
+        if dictionary.id != id {
+            return Err(anyhow!(
+                "Dictionary '{}' has ID {:08x} but patch requires {:08x}",
+                path,
+                dictionary.id,
+                id
+            ));
+        }
+        return Ok(dictionary);
+    }
+    for entry in std::fs::read_dir(path).context("Can't read dictionary directory")? {
+        let entry_path = entry?.path();
+        if !entry_path.is_file() {
+            continue;
+        }
+        if let Ok(dictionary) = std::fs::read(&entry_path).map(Dictionary::new) {
+            match dictionary {
+                Ok(dictionary) if dictionary.id == id => {
+                    status!("Using dictionary '{}'", entry_path.display());
+                    return Ok(dictionary);
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(anyhow!("Dictionary {:08x} was not found in '{}'", id, path))
+}
+
+fn train_dictionary(
+    output_filename: &str,
+    input_filenames: &[&str],
+    max_size: usize,
+) -> Result<()> {
+    let mut samples: Vec<Vec<u8>> = Vec::new();
+    for input_filename in input_filenames {
+        let data = std::fs::read(input_filename)
+            .with_context(|| format!("Can't read input file '{}'", input_filename))?;
+        // Patch files are compressed, so their decompressed sections are used instead
+        match patch_dictionary_id(&data) {
+            Ok(None) => samples.extend(
+                patch_training_samples(&data, None)
+                    .with_context(|| format!("Could not read patch file '{}'", input_filename))?,
+            ),
+            Ok(Some(_)) => status!(
+                "Skipping '{}' because it was compressed using a dictionary",
+                input_filename
+            ),
+            Err(_) => samples.push(data),
+        }
+    }
+    let total_size: usize = samples.iter().map(|sample| sample.len()).sum();
+    status!(
+        "Training dictionary on {:.2} MB of samples",
+        size_mb(total_size)
+    );
+    let dictionary = Dictionary::train(&samples, max_size).context("Could not train dictionary")?;
+    status!(
+        "Dictionary ID: {:08x}, size: {} bytes",
+        dictionary.id,
+        dictionary.data.len()
+    );
+    status!("Writing dictionary to '{}'", output_filename);
+    std::fs::write(output_filename, &dictionary.data).context("Could not write dictionary")?;
+    Ok(())
+}
+
 fn hash_file(filename: &str) -> Result<()> {
     let mmap = mmap_file_in(filename)?;
-    println!("File size {}", mmap.len());
+    status!("File size {}", mmap.len());
 
     let time_begin_blake3 = Instant::now();
     let mut hasher_blake3 = blake3::Hasher::new();
@@ -79,33 +244,33 @@ fn hash_file(filename: &str) -> Result<()> {
     let hash = hasher_blake3.finalize();
     let duration_blake3 = Instant::now() - time_begin_blake3;
 
-    println!(
+    status!(
         "Finished in {} sec, {} MB/sec",
         duration_blake3.as_secs_f32(),
         size_mb(mmap.len()) / duration_blake3.as_secs_f64()
     );
-    println!("Hash blake3: {}", hash.to_hex());
+    status!("Hash blake3: {}", hash.to_hex());
 
     let time_begin_rolling = Instant::now();
     let mut hash_rolling = RollingHash::new();
     hash_rolling.update(&mmap);
     let duration_rolling = Instant::now() - time_begin_rolling;
-    println!(
+    status!(
         "Finished in {} sec, {} MB/sec",
         duration_rolling.as_secs_f32(),
         size_mb(mmap.len()) / duration_rolling.as_secs_f64()
     );
-    println!("Hash rolling: {}", hash_rolling.get());
+    status!("Hash rolling: {}", hash_rolling.get());
 
     let time_begin_blocks = Instant::now();
     let blocks = compute_blocks(&mmap, DEFAULT_BLOCK_SIZE);
     let duration_blocks = Instant::now() - time_begin_blocks;
-    println!(
+    status!(
         "Finished computing blocks in {} sec, {} MB/sec",
         duration_blocks.as_secs_f32(),
         size_mb(mmap.len()) / duration_blocks.as_secs_f64()
     );
-    println!("Blocks: {}", blocks.len());
+    status!("Blocks: {}", blocks.len());
 
     hasher_blake3.reset();
     for block in blocks {
@@ -114,51 +279,241 @@ fn hash_file(filename: &str) -> Result<()> {
         hasher_blake3.update(&(block.hash_weak.to_le_bytes()));
         hasher_blake3.update(block.hash_strong.as_bytes());
     }
-    println!("Hash of blocks: {}", hasher_blake3.finalize().to_hex());
+    status!("Hash of blocks: {}", hasher_blake3.finalize().to_hex());
 
     Ok(())
 }
 
-fn diff_files(
-    base_filename: &str,
-    other_filename: &str,
-    patch_filename: Option<&str>,
+// Progress output, which is disabled when many files are diffed in parallel
+macro_rules! progress {
+    ($options:expr, $($arg:tt)*) => {
+        if $options.verbose {
+            status!($($arg)*);
+        }
+    };
+}
+
+struct DiffOptions {
     block_size: usize,
+    max_block_size: usize,
+    density: usize,
+    direction: DiffDirection,
+    align: usize,
+    codec: Codec,
     compression_level: i32,
-) -> Result<()> {
-    let base_mmap = mmap_file_in(base_filename).context("Can't open BASE input file")?;
-    println!(
-        "Base size: {:.2} MB ({} bytes)",
-        size_mb(base_mmap.len()),
-        base_mmap.len()
-    );
+    filter: Filter,
+    precomp: bool,
+    decompress_inputs: bool,
+    max_decompressed_size: u64,
+    masks: Vec<MaskSpec>,
+    reorder_literals: bool,
+    dictionary: Option<Dictionary>,
+    signature_cache: Option<SignatureCache>,
+    relocatable: bool,
+    seekable: bool,
+    verbose: bool,
+}
+
+impl DiffOptions {
+    fn params(&self) -> DiffParams {
+        DiffParams {
+            block_size_bounds: (self.block_size, self.max_block_size),
+            density: self.density,
+            align: self.align,
+            direction: self.direction,
+        }
+    }
+}
+
+// Diff input after masking, embedded stream expansion and filtering
+struct DiffInput {
+    file: FileIn,
+    hash: Hash128,
+    mask: Vec<MaskRange>,
+    streams: Vec<CompressedStream>,
+    transformed: Option<Vec<u8>>,
+    holes: Vec<Hole>,
+}
+
+impl DiffInput {
+    fn data(&self) -> &[u8] {
+        match &self.transformed {
+            Some(transformed) => transformed,
+            None => &self.file,
+        }
+    }
+}
 
-    let other_mmap = mmap_file_in(other_filename).context("Can't open OTHER input file")?;
-    println!(
-        "Other size: {:.2} MB ({} bytes)",
-        size_mb(other_mmap.len()),
-        other_mmap.len()
+fn read_diff_input(filename: &str, name: &str, options: &DiffOptions) -> Result<DiffInput> {
+    let file = read_file_in(
+        filename,
+        options.decompress_inputs,
+        options.max_decompressed_size,
+    )
+    .with_context(|| format!("Can't open {} input file", name))?;
+    progress!(
+        options,
+        "{} size: {:.2} MB ({} bytes)",
+        name,
+        size_mb(file.len()),
+        file.len()
     );
 
-    println!("Using block size: {}", block_size);
+    let mask = find_mask_ranges(&file, &options.masks);
+    if !options.masks.is_empty() {
+        let mask_size: u64 = mask.iter().map(|r| r.size).sum();
+        progress!(options, "Masked bytes in {}: {}", name, mask_size);
+    }
+    let masked = mask_data(&file, &mask);
 
-    println!("Computing block hashes for '{}'", other_filename);
-    let other_blocks = compute_blocks(&other_mmap, block_size);
+    let (streams, expanded) = if options.precomp {
+        progress!(
+            options,
+            "Searching for embedded compressed streams in {}",
+            name
+        );
+        let (streams, expanded) = find_expanded_streams(&masked);
+        progress!(
+            options,
+            "Embedded streams in {}: {} ({:.2} MB expanded)",
+            name,
+            streams.len(),
+            size_mb(expanded.len())
+        );
+        (streams, expanded)
+    } else {
+        (Vec::new(), Cow::Borrowed(&masked[..]))
+    };
+    let filtered = options.filter.encode(&expanded);
+    let transformed = match filtered {
+        Cow::Borrowed(data) if data.as_ptr() == file.as_ptr() => None,
+        filtered => Some(filtered.into_owned()),
+    };
 
-    println!("Computing diff");
-    let patch_commands = compute_diff(&base_mmap, &other_blocks, block_size);
+    // Holes are only skipped if transforms keep data offsets and zeros intact
+    let holes = match is_stdio(filename)
+        || file.decompressed.is_some()
+        || !streams.is_empty()
+        || options.filter != Filter::None
+    {
+        true => Vec::new(),
+        false => {
+            let sparse_file =
+                File::open(filename).with_context(|| format!("Can't open {} input file", name))?;
+            let holes = find_holes(&sparse_file)
+                .with_context(|| format!("Could not find holes in {} file", name))?;
+            align_holes(&holes, options.align)
+        }
+    };
+    if !holes.is_empty() {
+        let holes_size: u64 = holes.iter().map(|hole| hole.size).sum();
+        progress!(
+            options,
+            "Holes in {}: {:.2} MB ({} ranges)",
+            name,
+            size_mb(holes_size as usize),
+            holes.len()
+        );
+    }
 
-    if patch_commands.is_synchronized() {
-        println!("Patch is not required");
-        return Ok(());
+    let hash = compute_hash_strong_parallel(&file);
+    Ok(DiffInput {
+        file,
+        hash,
+        mask,
+        streams,
+        transformed,
+        holes,
+    })
+}
+
+struct DiffResult {
+    direction: DiffDirection,
+    diff_size: usize,
+    // Serialized patch, or None if inputs are identical
+    patch: Option<Vec<u8>>,
+}
+
+// OTHER signature is used in forward direction if provided
+fn diff_inputs(
+    base: &DiffInput,
+    other: &DiffInput,
+    other_signature: Option<&Signature>,
+    options: &DiffOptions,
+) -> Result<DiffResult> {
+    let filter = options.filter;
+    let base_data = base.data();
+    let other_data = other.data();
+
+    let params = options.params();
+    let direction = params.direction.resolve(base_data.len(), other_data.len());
+    // Commands of sparse inputs never cover OTHER holes, so they are empty even if inputs differ
+    let sparse = !base.holes.is_empty() || !other.holes.is_empty();
+    if sparse && base.hash == other.hash {
+        return Ok(DiffResult {
+            direction,
+            diff_size: 0,
+            patch: None,
+        });
+    }
+    progress!(options, "Computing diff (direction: {})", direction.name());
+    let (mut patch_commands, block_layout) = match other_signature {
+        _ if sparse => {
+            compute_diff_sparse(base_data, &base.holes, other_data, &other.holes, &params)
+        }
+        Some(signature) if direction == DiffDirection::Forward => {
+            let commands =
+                compute_diff_with_signature(base_data, other_data, signature, options.align);
+            (commands, signature.layout.clone())
+        }
+        _ => compute_diff_trimmed_with(base_data, other_data, &params),
+    };
+    if let BlockLayout::Adaptive {
+        block_sizes_log2, ..
+    } = &block_layout
+    {
+        for size in block_layout.block_sizes() {
+            let region_count = block_sizes_log2.iter().filter(|&&x| 1 << x == size).count();
+            progress!(options, "Block size {}: {} regions", size, region_count);
+        }
     }
 
-    println!(
+    if !sparse && patch_commands.is_synchronized() {
+        if base.hash == other.hash {
+            return Ok(DiffResult {
+                direction,
+                diff_size: 0,
+                patch: None,
+            });
+        }
+        // Inputs only differ in masked bytes or embedded stream parameters
+        patch_commands = PatchCommands::new_copy_base(other_data.len(), options.align);
+    }
+
+    progress!(
+        options,
+        "Common prefix: {:.2} MB, suffix: {:.2} MB",
+        size_mb(patch_commands.prefix),
+        size_mb(patch_commands.suffix)
+    );
+
+    progress!(
+        options,
         "Diff size: {:.2} MB",
         size_mb(patch_commands.need_bytes_from_other())
     );
 
-    println!(
+    if options.density > 1 && direction == DiffDirection::Forward {
+        progress!(
+            options,
+            "Recovered by overlapping signatures: {:.2} MB ({} bytes)",
+            size_mb(patch_commands.recovered),
+            patch_commands.recovered
+        );
+    }
+
+    progress!(
+        options,
         "Need from BASE: {:.2} MB ({} blocks), from OTHER: {:.2} MB ({} blocks)",
         size_mb(patch_commands.need_bytes_from_base()),
         patch_commands.base.len(),
@@ -166,169 +521,1215 @@ fn diff_files(
         patch_commands.other.len()
     );
 
-    let patch = build_patch(&other_mmap, &patch_commands);
-    println!("Patch commands: {}", patch.base.len() + patch.other.len());
+    let mut patch = build_patch(other_data, &patch_commands);
+    add_hole_fills(&mut patch, &other.holes);
+    if options.reorder_literals {
+        progress!(options, "Reordering literal data");
+        reorder_literals(&mut patch);
+    }
+    progress!(
+        options,
+        "Patch commands: {}",
+        patch.base.len() + patch.other.len() + patch.fill.len()
+    );
+    progress!(
+        options,
+        "Fill: {:.2} MB ({} commands)",
+        size_mb(patch.fill_bytes()),
+        patch.fill.len()
+    );
 
-    println!("Verifying patch");
-    let patched_base = apply_patch(&base_mmap, &patch);
-    if patched_base.len() != other_mmap.len() {
+    progress!(options, "Verifying patch");
+    let mut patched_base = apply_patch(base_data, &patch);
+    filter.decode(&mut patched_base);
+    let mut patched_base = restore_streams(patched_base, &other.streams)
+        .context("Could not restore embedded streams")?;
+    let other_masked_regions = extract_masked_regions(&other.file, &other.mask);
+    restore_masked_regions(&mut patched_base, &other_masked_regions);
+    if patched_base.len() != other.file.len() {
         return Err(anyhow!(
             "Patched base file size is {} but expected to be {}",
             patched_base.len(),
-            other_mmap.len()
+            other.file.len()
         ));
     }
 
-    let other_hash = compute_hash_strong(&other_mmap);
-    let patched_base_hash = compute_hash_strong(&patched_base);
-    if other_hash != patched_base_hash {
+    let patched_base_hash = compute_hash_strong_parallel(&patched_base);
+    if other.hash != patched_base_hash {
         return Err(anyhow!(
             "Patched base file hash is {:?} but expected to be {:?}",
-            other_hash,
+            other.hash,
             patched_base_hash
         ));
     }
 
-    println!("Serializing patch");
-    let patch_with_header = PatchWithHeader {
-        id: PATCH_FILE_ID,
-        version: PATCH_FILE_VERSION,
-        base_hash: compute_hash_strong(&base_mmap),
-        other_hash,
-        patch,
+    // Blocks refer to BASE data as it is copied from, which is only the file itself without
+    // transforms
+    let base_blocks = match options.relocatable {
+        true if filter != Filter::None || !base.mask.is_empty() || !base.streams.is_empty() => {
+            return Err(anyhow!(
+                "Relocatable patches can't be made with filters, masks or embedded streams"
+            ));
+        }
+        true => {
+            progress!(options, "Hashing referenced BASE blocks");
+            compute_source_blocks(base_data, &patch, RELOCATION_BLOCK_SIZE)
+        }
+        false => Vec::new(),
     };
-    let patch_serialized: Vec<u8> =
-        bincode::serialize(&patch_with_header).context("Could not deserialize patch file")?;
-    println!(
-        "Serialized uncompressed size: {:.2} MB",
-        size_mb(patch_serialized.len())
+
+    let other_regions = match options.seekable {
+        true => {
+            progress!(options, "Hashing OTHER regions");
+            RegionHashes::new(&other.file, REGION_HASH_SIZE)
+        }
+        false => RegionHashes::default(),
+    };
+
+    progress!(options, "Serializing patch");
+    let header = PatchHeader {
+        base_hash: base.hash,
+        other_hash: other.hash,
+        decompress_inputs: options.decompress_inputs,
+        base_mask: base.mask.clone(),
+        other_masked: other_masked_regions,
+        other_holes: other.holes.clone(),
+        other_regions,
+        base_blocks,
+        filter,
+        base_streams: base.streams.clone(),
+        other_streams: other.streams.clone(),
+        block_layout,
+    };
+    // Command lists are small, so they always use the strongest level of the selected codec
+    let codec = options.codec;
+    let commands_level = codec.level_bounds().1.min(COMMANDS_COMPRESSION_LEVEL);
+    let codecs = PatchCodecs {
+        header: SectionCodec::new(codec, commands_level),
+        commands: SectionCodec::new(codec, commands_level),
+        data: SectionCodec {
+            codec,
+            level: options.compression_level,
+            long_distance: true,
+        },
+        data_chunk_size: options.seekable.then_some(DATA_CHUNK_SIZE),
+    };
+    progress!(
+        options,
+        "Compressing patch ({} level {})",
+        codec.name(),
+        options.compression_level
     );
+    let (patch_compressed, section_stats) =
+        serialize_patch(header, &patch, &codecs, options.dictionary.as_ref())
+            .context("Could not serialize patch")?;
+    for section in &section_stats {
+        progress!(
+            options,
+            "Section '{}': {:.2} MB ({} bytes), compressed: {:.2} MB ({} bytes)",
+            section.name,
+            size_mb(section.size),
+            section.size,
+            size_mb(section.compressed_size),
+            section.compressed_size
+        );
+    }
+
+    progress!(
+        options,
+        "Compressed size: {:.2} MB",
+        size_mb(patch_compressed.len())
+    );
+
+    Ok(DiffResult {
+        direction,
+        diff_size: patch_commands.need_bytes_from_other(),
+        patch: Some(patch_compressed),
+    })
+}
 
-    println!("Compressing patch (zstd level {})", compression_level);
-    let patch_compressed =
-        compress(&patch_serialized, compression_level).context("Could not compress patch data")?;
+// Loads OTHER signature from the signature cache if it is enabled, or computes it and adds it
+// to the cache. Failing to update the cache is not an error.
+fn other_signature(other: &DiffInput, options: &DiffOptions) -> Signature {
+    let params = options.params();
+    let cache = match &options.signature_cache {
+        Some(cache) => cache,
+        None => {
+            status!("Computing OTHER signature");
+            return Signature::new(other.data(), &params);
+        }
+    };
+    let data_hash = match &other.transformed {
+        Some(transformed) => compute_hash_strong_parallel(transformed),
+        None => other.hash,
+    };
+    let data_size = other.data().len();
+    if let Some(signature) = cache.load(data_hash, data_size, &params) {
+        status!(
+            "Using cached OTHER signature '{}'",
+            cache.entry_path(data_hash, data_size, &params).display()
+        );
+        return signature;
+    }
+    status!("Computing OTHER signature");
+    let signature = Signature::new(other.data(), &params);
+    match cache.store(data_hash, data_size, &params, &signature) {
+        Ok(()) => status!(
+            "Added OTHER signature to cache '{}'",
+            cache.entry_path(data_hash, data_size, &params).display()
+        ),
+        Err(e) => status!("Could not add OTHER signature to cache: {}", e),
+    }
+    signature
+}
+
+fn print_diff_settings(options: &DiffOptions) {
+    if options.max_block_size > options.block_size {
+        status!(
+            "Using adaptive block size: {}..{}",
+            options.block_size,
+            options.max_block_size
+        );
+    } else {
+        status!("Using block size: {}", options.block_size);
+    }
+    if options.align > 1 {
+        status!("Using alignment: {}", options.align);
+    }
+    if options.filter != Filter::None {
+        status!("Using '{}' filter", options.filter.name());
+    }
+}
+
+fn write_stdout(data: &[u8]) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(data)
+        .and_then(|_| stdout.flush())
+        .context("Could not write data to stdout")
+}
+
+fn write_patch_file(patch_path: &std::path::Path, data: &[u8]) -> Result<()> {
+    let mut patch_file: std::fs::File =
+        File::create(patch_path).context("Can't open PATCH output file")?;
+    patch_file
+        .write_all(data)
+        .context("Could not write patch data to file")?;
+    Ok(())
+}
+
+fn diff_files(
+    base_filename: &str,
+    other_filename: &str,
+    patch_filename: Option<&str>,
+    options: &DiffOptions,
+) -> Result<()> {
+    let base = read_diff_input(base_filename, "BASE", options)?;
+    let other = read_diff_input(other_filename, "OTHER", options)?;
+    print_diff_settings(options);
 
-    println!("Compressed size: {:.2} MB", size_mb(patch_compressed.len()));
+    // Signature is only worth caching when OTHER is indexed
+    let direction = options
+        .direction
+        .resolve(base.data().len(), other.data().len());
+    let sparse = !base.holes.is_empty() || !other.holes.is_empty();
+    let signature = match options.signature_cache {
+        Some(_) if direction == DiffDirection::Forward && !sparse => {
+            Some(other_signature(&other, options))
+        }
+        _ => None,
+    };
+    let result = diff_inputs(&base, &other, signature.as_ref(), options)?;
+    let patch = match result.patch {
+        Some(patch) => patch,
+        None => {
+            status!("Patch is not required");
+            return Ok(());
+        }
+    };
 
     if let Some(patch_filename) = patch_filename {
-        println!("Writing patch to '{}'", patch_filename);
-        let mut patch_file: std::fs::File =
-            File::create(patch_filename).context("Can't open PATCH output file")?;
-        patch_file
-            .write_all(&patch_compressed)
+        if is_stdio(patch_filename) {
+            status!("Writing patch to stdout");
+            return write_stdout(&patch);
+        }
+        status!("Writing patch to '{}'", patch_filename);
+        write_patch_file(std::path::Path::new(patch_filename), &patch)?;
+    }
+
+    Ok(())
+}
+
+// Diffs many BASE files against one OTHER file in parallel, reusing OTHER signature
+fn diff_many_files(
+    other_filename: &str,
+    base_filenames: &[&str],
+    output_dir: &str,
+    options: &DiffOptions,
+) -> Result<()> {
+    let patch_filenames: Vec<std::path::PathBuf> = base_filenames
+        .iter()
+        .map(|base_filename| {
+            let name = std::path::Path::new(base_filename)
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid BASE file name '{}'", base_filename))?;
+            let mut patch_name = name.to_os_string();
+            patch_name.push(".patch");
+            Ok(std::path::Path::new(output_dir).join(patch_name))
+        })
+        .collect::<Result<_>>()?;
+    let unique_names: std::collections::HashSet<&std::path::PathBuf> =
+        patch_filenames.iter().collect();
+    if unique_names.len() != patch_filenames.len() {
+        return Err(anyhow!("BASE file names must be unique"));
+    }
+    std::fs::create_dir_all(output_dir).context("Can't create output directory")?;
+
+    status!("Reading '{}'", other_filename);
+    let other = read_diff_input(other_filename, "OTHER", options)?;
+    print_diff_settings(options);
+    // Diffs of sparse OTHER don't use the signature
+    let signature = match options.direction {
+        _ if !other.holes.is_empty() => None,
+        DiffDirection::Reverse => None,
+        _ => Some(other_signature(&other, options)),
+    };
+
+    status!("Diffing {} files", base_filenames.len());
+    let results: Vec<Result<(usize, DiffResult)>> = base_filenames
+        .par_iter()
+        .zip(patch_filenames.par_iter())
+        .map(|(base_filename, patch_filename)| {
+            let base = read_diff_input(base_filename, "BASE", options)?;
+            let result = diff_inputs(&base, &other, signature.as_ref(), options)?;
+            if let Some(patch) = &result.patch {
+                write_patch_file(patch_filename, patch)?;
+            }
+            Ok((base.file.len(), result))
+        })
+        .collect();
+
+    status!(
+        "{:<32} {:>12} {:>12} {:>12} {:>10}",
+        "BASE",
+        "Size (MB)",
+        "Diff (MB)",
+        "Patch (MB)",
+        "Direction"
+    );
+    let mut failed: usize = 0;
+    for (base_filename, result) in base_filenames.iter().zip(results.iter()) {
+        match result {
+            Ok((base_size, result)) => status!(
+                "{:<32} {:>12.2} {:>12.2} {:>12} {:>10}",
+                base_filename,
+                size_mb(*base_size),
+                size_mb(result.diff_size),
+                match &result.patch {
+                    Some(patch) => format!("{:.2}", size_mb(patch.len())),
+                    None => "identical".to_string(),
+                },
+                result.direction.name()
+            ),
+            Err(e) => {
+                failed += 1;
+                status!("{:<32} failed: {:#}", base_filename, e);
+            }
+        }
+    }
+    if failed != 0 {
+        return Err(anyhow!(
+            "Could not diff {} of {} files",
+            failed,
+            base_filenames.len()
+        ));
+    }
+    Ok(())
+}
+
+// Writes patched data to the output file, seeking over zero-filled regions instead of writing them
+// so that the file system can keep them sparse. Regions that are no longer zero after filter
+// decoding are written normally.
+fn write_output_file(
+    mut output_file: &File,
+    data: &[u8],
+    fill: &[FillCmd],
+    compression: FileCompression,
+) -> Result<()> {
+    if compression != FileCompression::None {
+        let compressed = compression
+            .compress(data)
+            .context("Could not compress output data")?;
+        output_file
+            .write_all(&compressed)
+            .context("Could not write patch data to file")?;
+        return Ok(());
+    }
+    let mut zero_fill: Vec<&FillCmd> = fill
+        .iter()
+        .filter(|cmd| {
+            let begin = cmd.target as usize;
+            let end = begin + cmd.size as usize;
+            cmd.byte == 0 && end <= data.len() && data[begin..end].iter().all(|&x| x == 0)
+        })
+        .collect();
+    zero_fill.sort_by_key(|cmd| cmd.target);
+    let mut write_begin: usize = 0;
+    for cmd in zero_fill {
+        let fill_begin = cmd.target as usize;
+        output_file
+            .write_all(&data[write_begin..fill_begin])
             .context("Could not write patch data to file")?;
+        write_begin = fill_begin + cmd.size as usize;
+        output_file
+            .seek(SeekFrom::Start(write_begin as u64))
+            .context("Could not seek in OUTPUT file")?;
     }
+    output_file
+        .write_all(&data[write_begin..])
+        .context("Could not write patch data to file")?;
+    output_file
+        .set_len(data.len() as u64)
+        .context("Could not set OUTPUT file size")?;
+    Ok(())
+}
+
+struct PatchOptions {
+    output_compression: FileCompression,
+    dictionary_path: Option<String>,
+    resume: bool,
+    in_place: bool,
+    metadata: MetadataOptions,
+    // Offset and size of the only part of OTHER that is reconstructed
+    range: Option<(u64, u64)>,
+    max_decompressed_size: u64,
+}
 
+// Sets metadata of the temporary output file and atomically moves it into place
+fn finish_output_file(
+    base_filename: &str,
+    output_file: File,
+    temp_path: &std::path::Path,
+    output_path: &std::path::Path,
+    metadata: &MetadataOptions,
+) -> Result<()> {
+    let base_file = File::open(base_filename).context("Can't open BASE file")?;
+    apply_metadata(&base_file, &output_file, metadata)
+        .context("Could not set OUTPUT file metadata")?;
+    commit_output(output_file, temp_path, output_path).context("Could not replace OUTPUT file")?;
     Ok(())
 }
 
+// Applies patch to a temporary output file without building the result in memory and replaces
+// OUTPUT with it once verified. Progress is recorded in a journal next to OUTPUT, so that
+// interrupted application can be resumed with the same temporary file.
+fn patch_file_direct(
+    base_filename: &str,
+    base_data: &[u8],
+    header: &PatchHeader,
+    patch: &Patch,
+    output_filename: &str,
+    journal_header: JournalHeader,
+    options: &PatchOptions,
+) -> Result<()> {
+    let base_file = File::open(base_filename).context("Can't open BASE file")?;
+    let output_path = std::path::Path::new(output_filename);
+    let journal_path = ApplyJournal::path_for(output_path);
+    let resumed = match options.resume && journal_path.exists() {
+        true => {
+            let (journal, completed) = ApplyJournal::open(&journal_path, journal_header)
+                .context("Can't resume patch application")?;
+            let output_file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(journal.temp_path())
+                .context("Can't resume patch application")?;
+            if output_file.metadata()?.len() != patch.other_size {
+                return Err(anyhow!(
+                    "Can't resume patch application: temporary OUTPUT file size differs"
+                ));
+            }
+            status!(
+                "Resuming patch application to '{}' ({} of {} commands completed)",
+                output_filename,
+                completed,
+                patch_command_count(patch)
+            );
+            Some((journal, output_file, completed))
+        }
+        false => None,
+    };
+    let (mut journal, output_file, completed) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            status!("Applying patch to '{}'", output_filename);
+            let (output_file, temp_path) =
+                create_output_temp(output_path).context("Can't open OUTPUT file")?;
+            output_file
+                .set_len(patch.other_size)
+                .context("Could not set OUTPUT file size")?;
+            let journal = ApplyJournal::create(&journal_path, journal_header, &temp_path)
+                .context("Can't create journal file")?;
+            (journal, output_file, 0)
+        }
+    };
+    let temp_path = journal.temp_path().to_path_buf();
+    let stats = apply_patch_to_file_from(
+        &base_file,
+        base_data,
+        patch,
+        &output_file,
+        completed,
+        Some(&mut |completed| journal.record(completed)),
+    )
+    .context("Could not write patch data to file")?;
+    let mut output = &output_file;
+    for region in &header.other_masked {
+        output
+            .seek(SeekFrom::Start(region.offset))
+            .context("Could not seek in OUTPUT file")?;
+        output
+            .write_all(&region.data)
+            .context("Could not write patch data to file")?;
+    }
+    punch_holes(&output_file, &header.other_holes)
+        .context("Could not punch holes in OUTPUT file")?;
+    status!(
+        "Cloned: {:.2} MB, copied: {:.2} MB, written: {:.2} MB",
+        size_mb(stats.cloned as usize),
+        size_mb(stats.copied as usize),
+        size_mb(stats.written as usize)
+    );
+
+    status!("Verifying result file");
+    let output_hash = compute_hash_strong_parallel(&mmap_file_in(&temp_path.to_string_lossy())?);
+    let _ = journal.remove();
+    if output_hash != header.other_hash {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(anyhow!(
+            "Patched file hash is {:?} but expected to be {:?}",
+            output_hash,
+            header.other_hash
+        ));
+    }
+    finish_output_file(
+        base_filename,
+        output_file,
+        &temp_path,
+        output_path,
+        &options.metadata,
+    )
+}
+
 fn patch_file(
     base_filename: &str,
     patch_filename: &str,
     output_filename: Option<&str>,
+    options: &PatchOptions,
 ) -> Result<()> {
-    let base_mmap = mmap_file_in(base_filename).context("Can't open BASE file")?;
+    let to_stdout = output_filename.is_some_and(is_stdio);
+    if to_stdout
+        && (options.resume || options.in_place || options.metadata != MetadataOptions::default())
+    {
+        return Err(anyhow!(
+            "Resume, in-place and metadata options can't be used when OUTPUT is stdout"
+        ));
+    }
+    if let Some(output_filename) = output_filename.filter(|_| !to_stdout) {
+        let same_file = is_same_file(
+            std::path::Path::new(base_filename),
+            std::path::Path::new(output_filename),
+        )
+        .context("Can't open OUTPUT file")?;
+        if same_file && !options.in_place {
+            return Err(anyhow!(
+                "OUTPUT is the same file as BASE, use --in-place to replace BASE"
+            ));
+        }
+    }
+
     let patch_mmap = mmap_file_in(patch_filename).context("Can't open PATCH file")?;
-    let patch_decompressed = decompress(&patch_mmap).context("Could not decompress patch file")?;
-    let patch_with_header: PatchWithHeader =
-        bincode::deserialize(&patch_decompressed).context("Could not deserialize patch file")?;
-    if patch_with_header.id != PATCH_FILE_ID || patch_with_header.version != PATCH_FILE_VERSION {
+    let dictionary = match patch_dictionary_id(&patch_mmap).context("Could not read patch file")? {
+        Some(id) => {
+            let path = options
+                .dictionary_path
+                .as_deref()
+                .ok_or_else(|| anyhow!("Patch requires dictionary {:08x}", id))?;
+            Some(find_dictionary(path, id)?)
+        }
+        None => None,
+    };
+    if options.range.is_some() {
+        return patch_file_range(
+            base_filename,
+            &patch_mmap,
+            dictionary.as_ref(),
+            output_filename,
+            options,
+        );
+    }
+    let (header, mut patch) =
+        deserialize_patch(&patch_mmap, dictionary.as_ref()).context("Could not read patch file")?;
+
+    let base_mmap = read_file_in(
+        base_filename,
+        header.decompress_inputs,
+        options.max_decompressed_size,
+    )
+    .context("Can't open BASE file")?;
+    // BASE ranges can be copied to the output directly if patch data is not transformed
+    let direct = options.output_compression == FileCompression::None
+        && base_mmap.decompressed.is_none()
+        && header.filter == Filter::None
+        && header.base_mask.is_empty()
+        && header.base_streams.is_empty()
+        && header.other_streams.is_empty();
+    let limits = match output_filename {
+        Some(_) if direct => PatchLimits::default(),
+        _ => PatchLimits::in_memory(),
+    };
+    validate_patch(&header, &patch, base_mmap.len() as u64, &limits)
+        .context("Invalid patch file")?;
+
+    let data_size = patch.data.len() as u64;
+    verify_base_file(&base_mmap, &header, &mut patch, data_size, &limits)?;
+
+    match output_filename {
+        Some(_) if direct && to_stdout => {
+            status!("Applying patch to stdout");
+            let output_hash = apply_patch_to_writer(
+                &base_mmap,
+                &patch,
+                &header.other_masked,
+                &mut std::io::stdout().lock(),
+            )
+            .context("Could not write data to stdout")?;
+            // Output is already written, so the consumer must check the exit status
+            if output_hash != header.other_hash {
+                return Err(anyhow!(
+                    "Patched output hash is {:?} but expected to be {:?}",
+                    output_hash,
+                    header.other_hash
+                ));
+            }
+            return Ok(());
+        }
+        Some(output_filename) if direct => {
+            let journal_header = JournalHeader {
+                patch_hash: compute_hash_strong_parallel(&patch_mmap),
+                other_hash: header.other_hash,
+                other_size: patch.other_size,
+            };
+            return patch_file_direct(
+                base_filename,
+                &base_mmap,
+                &header,
+                &patch,
+                output_filename,
+                journal_header,
+                options,
+            );
+        }
+        _ if options.resume => {
+            status!("Patch can't be resumed, since the result is built in memory")
+        }
+        _ => {}
+    }
+
+    status!("Applying patch");
+    let filter = header.filter;
+    let base_masked = mask_data(&base_mmap, &header.base_mask);
+    let base_expanded = expand_streams(&base_masked, &header.base_streams)
+        .context("Could not expand BASE streams")?;
+    let mut patched_base = apply_patch(&filter.encode(&base_expanded), &patch);
+    filter.decode(&mut patched_base);
+    let mut patched_base = restore_streams(patched_base, &header.other_streams)
+        .context("Could not restore embedded streams")?;
+    restore_masked_regions(&mut patched_base, &header.other_masked);
+
+    status!("Verifying result file");
+    let patched_base_hash = compute_hash_strong_parallel(&patched_base);
+    if patched_base_hash != header.other_hash {
         return Err(anyhow!(
-            "Patch header is [{:?} v{}] but expected to be [{:?} v{}]",
-            patch_with_header.id,
-            patch_with_header.version,
-            PATCH_FILE_ID,
-            PATCH_FILE_VERSION
+            "Patched file hash is {:?} but expected to be {:?}",
+            patched_base_hash,
+            header.other_hash
         ));
     }
 
-    println!("Verifying base file");
-    let base_hash = compute_hash_strong(&base_mmap);
-    if base_hash != patch_with_header.base_hash {
+    let fill = restored_fill_cmds(&patch.fill, &header.other_streams);
+    write_output(base_filename, output_filename, &patched_base, fill, options)
+}
+
+// Relocatable patches are moved to the data they copy if BASE was modified. Since BASE hash
+// can't be checked then, the patch is validated again and the output hash check is what
+// guarantees the result.
+fn verify_base_file(
+    base_data: &[u8],
+    header: &PatchHeader,
+    patch: &mut Patch,
+    data_size: u64,
+    limits: &PatchLimits,
+) -> Result<()> {
+    status!("Verifying base file");
+    let base_hash = compute_hash_strong_parallel(base_data);
+    if base_hash == header.base_hash {
+        return Ok(());
+    }
+    if header.base_blocks.is_empty() {
         return Err(anyhow!(
             "Base file hash is {:?} but expected to be {:?}",
             base_hash,
-            patch_with_header.base_hash
+            header.base_hash
         ));
     }
+    status!("Base file is modified, relocating the data that the patch copies");
+    let stats = relocate_patch(base_data, patch, &header.base_blocks)
+        .context("Patch can't be applied to modified BASE")?;
+    status!(
+        "BASE blocks intact: {}, relocated: {}",
+        stats.intact,
+        stats.relocated
+    );
+    validate_patch_commands(header, patch, data_size, base_data.len() as u64, limits)
+        .context("Invalid relocated patch")?;
+    Ok(())
+}
 
-    println!("Applying patch");
-    let patched_base = apply_patch(&base_mmap, &patch_with_header.patch);
+// Writes verified output data to stdout or to a temporary file that replaces OUTPUT
+fn write_output(
+    base_filename: &str,
+    output_filename: Option<&str>,
+    data: &[u8],
+    fill: &[FillCmd],
+    options: &PatchOptions,
+) -> Result<()> {
+    let output_filename = match output_filename {
+        Some(output_filename) => output_filename,
+        None => return Ok(()),
+    };
+    if is_stdio(output_filename) {
+        status!("Writing output to stdout");
+        return match options.output_compression {
+            FileCompression::None => write_stdout(data),
+            compression => write_stdout(
+                &compression
+                    .compress(data)
+                    .context("Could not compress output data")?,
+            ),
+        };
+    }
+    status!("Writing output to '{}'", output_filename);
+    let output_path = std::path::Path::new(output_filename);
+    let (output_file, temp_path) =
+        create_output_temp(output_path).context("Can't open OUTPUT file")?;
+    let result =
+        write_output_file(&output_file, data, fill, options.output_compression).and_then(|_| {
+            finish_output_file(
+                base_filename,
+                output_file,
+                &temp_path,
+                output_path,
+                &options.metadata,
+            )
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
 
-    println!("Verifying result file");
-    let patched_base_hash = compute_hash_strong(&patched_base);
-    if patched_base_hash != patch_with_header.other_hash {
+// Reconstructs only a range of OTHER, decompressing only the literal data that it needs. The
+// range can't be checked against OTHER hash, so regions that contain it are verified instead.
+fn patch_file_range(
+    base_filename: &str,
+    patch_data: &[u8],
+    dictionary: Option<&Dictionary>,
+    output_filename: Option<&str>,
+    options: &PatchOptions,
+) -> Result<()> {
+    let (offset, size) = options.range.unwrap();
+    let limits = PatchLimits::default();
+    let (header, mut patch, literals) = deserialize_patch_seekable(patch_data, dictionary, &limits)
+        .context("Could not read patch file")?;
+    if header.filter != Filter::None || !header.other_streams.is_empty() {
         return Err(anyhow!(
-            "Patched file hash is {:?} but expected to be {:?}",
-            patched_base_hash,
-            patch_with_header.other_hash
+            "Range can't be reconstructed from patches with filters or embedded streams"
         ));
     }
-
-    if let Some(output_filename) = output_filename {
-        println!("Writing output to '{}'", output_filename);
-        let mut patch_file: std::fs::File =
-            File::create(output_filename).context("Can't open OUTPUT file")?;
-        patch_file
-            .write_all(&patched_base)
-            .context("Could not write patch data to file")?;
+    if header.other_regions.is_empty() {
+        return Err(anyhow!(
+            "Patch has no region hashes to verify the range, it must be made with --seekable"
+        ));
     }
+    let end = offset
+        .checked_add(size)
+        .filter(|&end| end <= patch.other_size)
+        .ok_or_else(|| anyhow!("Range is outside of OTHER ({} bytes)", patch.other_size))?;
 
-    Ok(())
+    let base_mmap = read_file_in(
+        base_filename,
+        header.decompress_inputs,
+        options.max_decompressed_size,
+    )
+    .context("Can't open BASE file")?;
+    validate_patch_commands(
+        &header,
+        &patch,
+        literals.size(),
+        base_mmap.len() as u64,
+        &limits,
+    )
+    .context("Invalid patch file")?;
+    verify_base_file(&base_mmap, &header, &mut patch, literals.size(), &limits)?;
+
+    let (region_begin, region_end) =
+        header
+            .other_regions
+            .covering_range(offset, end, patch.other_size);
+    status!(
+        "Applying patch to range {}..{} (regions {}..{})",
+        offset,
+        end,
+        region_begin,
+        region_end
+    );
+    let mut clipped = clip_patch_commands(&patch, region_begin, region_end);
+    literals
+        .load_literals(&mut clipped)
+        .context("Could not read patch data")?;
+    status!(
+        "Commands: {} of {}, literal data: {:.2} MB of {:.2} MB",
+        patch_command_count(&clipped),
+        patch_command_count(&patch),
+        size_mb(clipped.data.len()),
+        size_mb(literals.size() as usize)
+    );
+    let base_masked = mask_data(&base_mmap, &header.base_mask);
+    let base_expanded = expand_streams(&base_masked, &header.base_streams)
+        .context("Could not expand BASE streams")?;
+    let mut result = apply_patch(&base_expanded, &clipped);
+    let masked = clip_masked_regions(&header.other_masked, region_begin, region_end);
+    restore_masked_regions(&mut result, &masked);
+
+    status!("Verifying result range");
+    header
+        .other_regions
+        .verify(&result, region_begin)
+        .map_err(|offset| anyhow!("Patched region at offset {} doesn't match its hash", offset))?;
+    let range = (offset - region_begin) as usize..(end - region_begin) as usize;
+    write_output(base_filename, output_filename, &result[range], &[], options)
 }
 
 fn clamp_parameter(name: &str, v: i32, bounds: (i32, i32)) -> i32 {
     let clamped = min(max(bounds.0, v), bounds.1);
     if v != clamped {
-        println!(
+        status!(
             "{} ({}) is outside of expected range [{}..{}] and was clamped to {}",
-            name, v, bounds.0, bounds.1, clamped
+            name,
+            v,
+            bounds.0,
+            bounds.1,
+            clamped
         )
     }
     clamped
 }
 
+fn parse_diff_options(matches: &clap::ArgMatches) -> Result<DiffOptions> {
+    let block_size = match matches.value_of("block") {
+        Some(block_str) => {
+            let block_size_log2 = block_str
+                .parse::<i32>()
+                .context("Couldn't parse block size parameter into integer")?;
+            1 << clamp_parameter("Block size", block_size_log2, BLOCK_SIZE_BOUNDS_LOG2)
+        }
+        None => 1 << DEFAULT_BLOCK_SIZE_LOG2,
+    };
+    let align: usize = match matches.value_of("align") {
+        Some(align_str) => {
+            let align_log2 = align_str
+                .parse::<i32>()
+                .context("Couldn't parse alignment parameter into integer")?;
+            1 << clamp_parameter("Alignment", align_log2, ALIGN_BOUNDS_LOG2)
+        }
+        None => 1,
+    };
+    let block_size = if align > block_size {
+        status!(
+            "Block size ({}) is smaller than alignment and was increased to {}",
+            block_size,
+            align
+        );
+        align
+    } else {
+        block_size
+    };
+    let max_block_size: usize = match matches.value_of("max_block") {
+        Some(block_str) => {
+            let block_size_log2 = block_str
+                .parse::<i32>()
+                .context("Couldn't parse maximum block size parameter into integer")?;
+            let max_block_size: usize = 1
+                << clamp_parameter(
+                    "Maximum block size",
+                    block_size_log2,
+                    BLOCK_SIZE_BOUNDS_LOG2,
+                );
+            max(max_block_size, block_size)
+        }
+        None => block_size,
+    };
+    let codec = match matches.value_of("codec") {
+        Some(codec_str) => {
+            Codec::from_name(codec_str).ok_or_else(|| anyhow!("Unknown codec '{}'", codec_str))?
+        }
+        None => Codec::Zstd,
+    };
+    let compression_level = match matches.value_of("level") {
+        Some(level_str) => {
+            let level = level_str
+                .parse::<i32>()
+                .context("Couldn't parse compression level parameter into integer")?;
+            clamp_parameter("Compression level", level, codec.level_bounds())
+        }
+        None => codec.default_level(),
+    };
+    let filter = match matches.value_of("filter") {
+        Some(filter_str) => Filter::from_name(filter_str)
+            .ok_or_else(|| anyhow!("Unknown filter '{}'", filter_str))?,
+        None => Filter::None,
+    };
+    let masks = match matches.values_of("mask") {
+        Some(values) => values
+            .map(|mask_str| {
+                MaskSpec::parse(mask_str)
+                    .ok_or_else(|| anyhow!("Couldn't parse mask specification '{}'", mask_str))
+            })
+            .collect::<Result<Vec<MaskSpec>>>()?,
+        None => Vec::new(),
+    };
+    let dictionary = match matches.value_of("dict") {
+        Some(_) if codec != Codec::Zstd => {
+            return Err(anyhow!("Dictionaries can only be used with zstd codec"));
+        }
+        Some(dict_str) => Some(load_dictionary(dict_str)?),
+        None => None,
+    };
+    let density = match matches.value_of("density") {
+        Some(density_str) => {
+            let density = density_str
+                .parse::<i32>()
+                .context("Couldn't parse signature density parameter into integer")?;
+            clamp_parameter("Signature density", density, DENSITY_BOUNDS) as usize
+        }
+        None => 1,
+    };
+    let direction = match matches.value_of("direction") {
+        Some(direction_str) => DiffDirection::from_name(direction_str)
+            .ok_or_else(|| anyhow!("Unknown diff direction '{}'", direction_str))?,
+        None => DiffDirection::Auto,
+    };
+    Ok(DiffOptions {
+        block_size,
+        max_block_size,
+        density,
+        direction,
+        align,
+        codec,
+        compression_level,
+        filter,
+        precomp: matches.is_present("precomp"),
+        decompress_inputs: !matches.is_present("raw"),
+        max_decompressed_size: parse_max_decompressed_size(matches)?,
+        masks,
+        reorder_literals: matches.is_present("group"),
+        dictionary,
+        signature_cache: matches.value_of("cache").map(SignatureCache::new),
+        relocatable: matches.is_present("relocatable"),
+        seekable: matches.is_present("seekable"),
+        verbose: true,
+    })
+}
+
+fn parse_max_decompressed_size(matches: &clap::ArgMatches) -> Result<u64> {
+    match matches.value_of("max_decompressed") {
+        Some(size_str) => parse_u64(size_str)
+            .ok_or_else(|| anyhow!("Couldn't parse decompressed size limit into integer")),
+        None => Ok(DEFAULT_MAX_DECOMPRESSED_FILE_SIZE),
+    }
+}
+
+fn parse_patch_options(matches: &clap::ArgMatches) -> Result<PatchOptions> {
+    let output_compression = match matches.value_of("compress") {
+        Some(compression_str) => FileCompression::from_name(compression_str)
+            .ok_or_else(|| anyhow!("Unknown compression format '{}'", compression_str))?,
+        None => FileCompression::None,
+    };
+    let in_place = matches.is_present("in_place");
+    let preserve = match matches.values_of("preserve") {
+        Some(values) => {
+            let mut preserve = PreserveMetadata::default();
+            for name in values {
+                if !preserve.add(name) {
+                    return Err(anyhow!("Unknown file attribute '{}'", name));
+                }
+            }
+            preserve
+        }
+        // Replaced BASE keeps its attributes by default
+        None if in_place => PreserveMetadata::all(),
+        None => PreserveMetadata::default(),
+    };
+    let mode = match matches.value_of("mode") {
+        Some(mode_str) => Some(
+            u32::from_str_radix(mode_str, 8)
+                .context("Couldn't parse file mode parameter into octal integer")?,
+        ),
+        None => None,
+    };
+    let owner = match matches.value_of("owner") {
+        Some(owner_str) => {
+            let parse_id = |id_str: &str| {
+                id_str
+                    .parse::<u32>()
+                    .context("Couldn't parse owner parameter into UID[:GID]")
+            };
+            Some(match owner_str.split_once(':') {
+                Some((uid_str, gid_str)) => (parse_id(uid_str)?, Some(parse_id(gid_str)?)),
+                None => (parse_id(owner_str)?, None),
+            })
+        }
+        None => None,
+    };
+    let mtime = match matches.value_of("mtime") {
+        Some(mtime_str) => {
+            let seconds = mtime_str
+                .parse::<u64>()
+                .context("Couldn't parse modification time parameter into integer")?;
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+        }
+        None => None,
+    };
+    let range = match matches.value_of("range") {
+        Some(range_str) => {
+            let range = range_str
+                .split_once(':')
+                .and_then(|(offset, size)| Some((parse_u64(offset)?, parse_u64(size)?)));
+            Some(range.ok_or_else(|| {
+                anyhow!("Couldn't parse range parameter into OFFSET:LEN integers")
+            })?)
+        }
+        None => None,
+    };
+    if range.is_some() && (in_place || matches.is_present("resume")) {
+        return Err(anyhow!(
+            "--in-place and --resume can't be used with --range"
+        ));
+    }
+    Ok(PatchOptions {
+        output_compression,
+        dictionary_path: matches.value_of("dict").map(str::to_string),
+        resume: matches.is_present("resume"),
+        in_place,
+        metadata: MetadataOptions {
+            preserve,
+            mode,
+            owner,
+            mtime,
+        },
+        range,
+        max_decompressed_size: parse_max_decompressed_size(matches)?,
+    })
+}
+
 fn dispatch_command(matches: clap::ArgMatches) -> Result<()> {
     if let Some(matches) = matches.subcommand_matches("hash") {
         let input = matches.value_of("INPUT").unwrap();
-        println!("Hashing '{}'", input);
+        status!("Hashing '{}'", input);
         return hash_file(input);
+    } else if let Some(matches) = matches.subcommand_matches("train-dict") {
+        let output = matches.value_of("OUTPUT").unwrap();
+        let inputs: Vec<&str> = matches.values_of("INPUT").unwrap().collect();
+        let max_size = match matches.value_of("size") {
+            Some(size_str) => size_str
+                .parse::<usize>()
+                .context("Couldn't parse dictionary size parameter into integer")?,
+            None => DEFAULT_DICTIONARY_SIZE,
+        };
+        return train_dictionary(output, &inputs, max_size);
     } else if let Some(matches) = matches.subcommand_matches("patch") {
         let base = matches.value_of("BASE").unwrap();
         let patch = matches.value_of("PATCH").unwrap();
         let output = matches.value_of("OUTPUT");
-        println!("Patching '{}' using '{}'", base, patch);
-        return patch_file(base, patch, output);
+        if is_stdio(base) {
+            return Err(anyhow!("BASE can't be read from stdin"));
+        }
+        if output.is_some_and(is_stdio) {
+            STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+        }
+        let options = parse_patch_options(matches)?;
+        status!("Patching '{}' using '{}'", base, patch);
+        return patch_file(base, patch, output, &options);
     } else if let Some(matches) = matches.subcommand_matches("diff") {
         let base = matches.value_of("BASE").unwrap();
         let other = matches.value_of("OTHER").unwrap();
         let patch = matches.value_of("PATCH");
-        let block_size = match matches.value_of("block") {
-            Some(block_str) => {
-                let block_size_log2 = block_str
-                    .parse::<i32>()
-                    .context("Couldn't parse block size parameter into integer")?;
-                1 << clamp_parameter("Block size", block_size_log2, BLOCK_SIZE_BOUNDS_LOG2)
-            }
-            None => 1 << DEFAULT_BLOCK_SIZE_LOG2,
-        };
-        let compression_level = match matches.value_of("level") {
-            Some(level_str) => {
-                let level = level_str
-                    .parse::<i32>()
-                    .context("Couldn't parse compression level parameter into integer")?;
-                clamp_parameter("Compression level", level, COMPRESSION_LEVEL_BOUNDS)
-            }
-            None => DEFAULT_COMPRESSION_LEVEL,
-        };
-        println!("Diffing '{}' and '{}'", base, other);
-        return diff_files(base, other, patch, block_size, compression_level);
+        if is_stdio(base) && is_stdio(other) {
+            return Err(anyhow!("Only one of BASE and OTHER can be read from stdin"));
+        }
+        if patch.is_some_and(is_stdio) {
+            STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+        }
+        let options = parse_diff_options(matches)?;
+        status!("Diffing '{}' and '{}'", base, other);
+        return diff_files(base, other, patch, &options);
+    } else if let Some(matches) = matches.subcommand_matches("diff-many") {
+        let other = matches.value_of("OTHER").unwrap();
+        let bases: Vec<&str> = matches.values_of("BASE").unwrap().collect();
+        let output_dir = matches.value_of("output").unwrap();
+        let mut options = parse_diff_options(matches)?;
+        options.verbose = false;
+        return diff_many_files(other, &bases, output_dir, &options);
     }
     Ok(())
 }
 
+// Help strings of diff arguments that are formatted at run time
+struct DiffHelp {
+    level: String,
+    block: String,
+    max_block: String,
+    density: String,
+    align: String,
+    max_decompressed: String,
+}
+
+impl DiffHelp {
+    fn new() -> Self {
+        Self {
+            level: format!(
+                "Compression level, zstd: [{}..{}], default = {}, xz: [{}..{}], default = {}",
+                Codec::Zstd.level_bounds().0,
+                Codec::Zstd.level_bounds().1,
+                Codec::Zstd.default_level(),
+                Codec::Xz.level_bounds().0,
+                Codec::Xz.level_bounds().1,
+                Codec::Xz.default_level()
+            ),
+            block: format!(
+                "Patch block size as log2(bytes) [{}..{}], default = {} ({} bytes)",
+                BLOCK_SIZE_BOUNDS_LOG2.0,
+                BLOCK_SIZE_BOUNDS_LOG2.1,
+                DEFAULT_BLOCK_SIZE_LOG2,
+                1 << DEFAULT_BLOCK_SIZE_LOG2
+            ),
+            max_block: format!(
+                "Enables adaptive block size between block size (-b) and this maximum as log2(bytes) [{}..{}], chosen per region of OTHER by entropy",
+                BLOCK_SIZE_BOUNDS_LOG2.0,
+                BLOCK_SIZE_BOUNDS_LOG2.1,
+            ),
+            density: format!(
+                "Number of overlapping OTHER signatures per block [{}..{}], default = 1 (no overlap)",
+                DENSITY_BOUNDS.0, DENSITY_BOUNDS.1,
+            ),
+            align: format!(
+                "Alignment of BASE sources and patch targets as log2(bytes) [{}..{}], default = 0 (1 byte)",
+                ALIGN_BOUNDS_LOG2.0,
+                ALIGN_BOUNDS_LOG2.1,
+            ),
+            max_decompressed: format!(
+                "Largest decompressed size of zstd and gzip inputs in bytes, default = {}",
+                DEFAULT_MAX_DECOMPRESSED_FILE_SIZE
+            ),
+        }
+    }
+}
+
+// Arguments shared by 'diff' and 'diff-many' commands
+fn diff_args(help: &DiffHelp) -> Vec<Arg<'_, '_>> {
+    vec![
+        Arg::with_name("level")
+            .short("l")
+            .takes_value(true)
+            .help(&help.level),
+        Arg::with_name("codec")
+            .short("c")
+            .long("codec")
+            .takes_value(true)
+            .possible_values(&Codec::NAMES)
+            .help("Patch compression codec, default = zstd"),
+        Arg::with_name("dict")
+            .short("d")
+            .long("dict")
+            .takes_value(true)
+            .help("Compress patch using a zstd dictionary created by 'train-dict' command"),
+        Arg::with_name("block")
+            .short("b")
+            .takes_value(true)
+            .help(&help.block),
+        Arg::with_name("max_block")
+            .short("B")
+            .long("max-block")
+            .takes_value(true)
+            .help(&help.max_block),
+        Arg::with_name("density")
+            .short("n")
+            .long("density")
+            .takes_value(true)
+            .help(&help.density),
+        Arg::with_name("direction")
+            .short("i")
+            .long("direction")
+            .takes_value(true)
+            .possible_values(&DiffDirection::NAMES)
+            .help("Index OTHER and scan BASE (forward) or index BASE and scan OTHER (reverse), default = auto (reverse if BASE is much larger than OTHER)"),
+        Arg::with_name("align")
+            .short("a")
+            .takes_value(true)
+            .help(&help.align),
+        Arg::with_name("filter")
+            .short("f")
+            .takes_value(true)
+            .possible_values(&Filter::NAMES)
+            .help("Reversible filter applied to inputs before diffing, default = none"),
+        Arg::with_name("mask")
+            .short("m")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Bytes ignored during matching as OFFSET:SIZE or pattern:HEX:SIZE (bytes following each occurrence of the pattern)"),
+        Arg::with_name("group")
+            .short("g")
+            .help("Group similar literal data together before compression"),
+        Arg::with_name("precomp")
+            .short("p")
+            .help("Diff contents of embedded zlib and zstd streams that can be recompressed bit-exactly"),
+        Arg::with_name("raw")
+            .short("r")
+            .help("Treat zstd and gzip compressed inputs as raw data instead of diffing their contents"),
+        Arg::with_name("max_decompressed")
+            .long("max-decompressed")
+            .takes_value(true)
+            .help(&help.max_decompressed),
+        Arg::with_name("cache")
+            .short("k")
+            .long("cache")
+            .takes_value(true)
+            .env("PATCHY_CACHE_DIR")
+            .help("Directory where OTHER signatures are cached and reused by later diffs of the same OTHER"),
+        Arg::with_name("relocatable")
+            .long("relocatable")
+            .help("Store hashes of BASE blocks that the patch copies, so that it can be applied to a modified BASE"),
+        Arg::with_name("seekable")
+            .long("seekable")
+            .help("Compress literal data in independent chunks and store OTHER region hashes, so that ranges of OTHER can be reconstructed with --range"),
+    ]
+}
+
 fn main() {
     let time_begin = Instant::now();
+    let diff_help = DiffHelp::new();
+    let diff_args = diff_args(&diff_help);
     match dispatch_command(
         App::new("Patchy")
             .version(env!("CARGO_PKG_VERSION"))
@@ -339,49 +1740,133 @@ fn main() {
                     .about("Computes block hash for a file")
                     .arg(Arg::with_name("INPUT").required(true).help("Input file")),
             )
+            .subcommand(
+                SubCommand::with_name("train-dict")
+                    .about("Trains a zstd dictionary on a set of patch files or literal data samples")
+                    .arg(
+                        Arg::with_name("size")
+                            .short("s")
+                            .takes_value(true)
+                            .help(&format!(
+                                "Maximum dictionary size in bytes, default = {}",
+                                DEFAULT_DICTIONARY_SIZE
+                            )),
+                    )
+                    .arg(Arg::with_name("OUTPUT").required(true).help("Output dictionary file"))
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .required(true)
+                            .multiple(true)
+                            .help("Sample files"),
+                    ),
+            )
             .subcommand(
                 SubCommand::with_name("patch")
                     .about("Applies a patch created by 'diff' command")
+                    .arg(
+                        Arg::with_name("compress")
+                            .short("z")
+                            .takes_value(true)
+                            .possible_values(&FileCompression::NAMES)
+                            .help("Compression format of the output file, default = none"),
+                    )
+                    .arg(
+                        Arg::with_name("dict")
+                            .short("d")
+                            .long("dict")
+                            .takes_value(true)
+                            .help("Dictionary file or directory that contains the dictionary required by the patch"),
+                    )
+                    .arg(
+                        Arg::with_name("resume")
+                            .long("resume")
+                            .help("Continue interrupted patch application using the journal next to OUTPUT"),
+                    )
+                    .arg(
+                        Arg::with_name("in_place")
+                            .long("in-place")
+                            .help("Allow OUTPUT to be the same file as BASE, which is replaced once patching succeeds"),
+                    )
+                    .arg(
+                        Arg::with_name("preserve")
+                            .short("P")
+                            .long("preserve")
+                            .takes_value(true)
+                            .multiple(true)
+                            .use_delimiter(true)
+                            .possible_values(&PreserveMetadata::NAMES)
+                            .help("BASE file attributes to copy to OUTPUT, default = all with --in-place, none otherwise"),
+                    )
+                    .arg(
+                        Arg::with_name("mode")
+                            .long("mode")
+                            .takes_value(true)
+                            .help("OUTPUT file permissions as an octal number"),
+                    )
+                    .arg(
+                        Arg::with_name("owner")
+                            .long("owner")
+                            .takes_value(true)
+                            .help("OUTPUT file owner as UID[:GID]"),
+                    )
+                    .arg(
+                        Arg::with_name("mtime")
+                            .long("mtime")
+                            .takes_value(true)
+                            .help("OUTPUT file modification time as seconds since Unix epoch"),
+                    )
+                    .arg(
+                        Arg::with_name("max_decompressed")
+                            .long("max-decompressed")
+                            .takes_value(true)
+                            .help(&diff_help.max_decompressed),
+                    )
+                    .arg(
+                        Arg::with_name("range")
+                            .long("range")
+                            .takes_value(true)
+                            .help("Only reconstruct LEN bytes of the output starting at OFFSET, as OFFSET:LEN"),
+                    )
                     .arg(Arg::with_name("BASE").required(true).help("Base file"))
-                    .arg(Arg::with_name("PATCH").required(true).help("Patch file"))
-                    .arg(Arg::with_name("OUTPUT").help("Output file")),
+                    .arg(Arg::with_name("PATCH").required(true).help("Patch file, - reads it from stdin"))
+                    .arg(Arg::with_name("OUTPUT").help("Output file, - writes it to stdout")),
             )
             .subcommand(
                 SubCommand::with_name("diff")
                     .about("Computes binary difference between files and writes patch file to disk")
+                    .args(&diff_args)
+                    .arg(Arg::with_name("BASE").required(true).help("Base file"))
+                    .arg(Arg::with_name("OTHER").required(true).help("Other file, - reads it from stdin"))
+                    .arg(Arg::with_name("PATCH").help("Output patch file, - writes it to stdout")),
+            )
+            .subcommand(
+                SubCommand::with_name("diff-many")
+                    .about("Computes patches from many BASE files to one OTHER file in parallel, reusing OTHER signature")
+                    .args(&diff_args)
                     .arg(
-                        Arg::with_name("level")
-                            .short("l")
-                            .takes_value(true)
-                            .help(&format!(
-                                "Compression level [{}..{}], default = {}",
-                                COMPRESSION_LEVEL_BOUNDS.0,
-                                COMPRESSION_LEVEL_BOUNDS.1,
-                                DEFAULT_COMPRESSION_LEVEL
-                            )),
-                    )
-                    .arg(
-                        Arg::with_name("block")
-                            .short("b")
+                        Arg::with_name("output")
+                            .short("o")
                             .takes_value(true)
-                            .help(&format!(
-                                "Patch block size as log2(bytes) [{}..{}], default = {} ({} bytes)",
-                                BLOCK_SIZE_BOUNDS_LOG2.0,
-                                BLOCK_SIZE_BOUNDS_LOG2.1,
-                                DEFAULT_BLOCK_SIZE_LOG2,
-                                1 << DEFAULT_BLOCK_SIZE_LOG2
-                            )),
+                            .required(true)
+                            .help("Output directory, patch for each BASE is written as <BASE file name>.patch"),
                     )
-                    .arg(Arg::with_name("BASE").required(true).help("Base file"))
                     .arg(Arg::with_name("OTHER").required(true).help("Other file"))
-                    .arg(Arg::with_name("PATCH").help("Output patch file")),
+                    .arg(
+                        Arg::with_name("BASE")
+                            .required(true)
+                            .multiple(true)
+                            .help("Base files"),
+                    ),
             )
             .get_matches(),
     ) {
-        Ok(_) => println!(
+        Ok(_) => status!(
             "Success (took {:.2} seconds)",
             (Instant::now() - time_begin).as_secs_f64()
         ),
-        Err(e) => println!("Failed: {:?}", e),
+        Err(e) => {
+            status!("Failed: {:?}", e);
+            std::process::exit(1);
+        }
     }
 }
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::min;
+
+// Volatile bytes (timestamps, GUIDs, signatures) that are ignored during block matching
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MaskSpec {
+    // Fixed file range
+    Range { offset: u64, size: u64 },
+    // Bytes that follow every occurrence of a byte pattern
+    Pattern { pattern: Vec<u8>, size: u64 },
+}
+
+// Parses decimal or 0x-prefixed hex number
+pub fn parse_u64(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse::<u64>().ok()
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl MaskSpec {
+    // Parses `OFFSET:SIZE` or `pattern:HEX:SIZE`, numbers may be decimal or 0x-prefixed hex
+    pub fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[..] {
+            [offset, size] => Some(MaskSpec::Range {
+                offset: parse_u64(offset)?,
+                size: parse_u64(size)?,
+            }),
+            ["pattern", pattern, size] => Some(MaskSpec::Pattern {
+                pattern: parse_hex(pattern)?,
+                size: parse_u64(size)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaskRange {
+    pub offset: u64,
+    pub size: u64,
+}
+
+// Original contents of a masked range, used to restore exact bytes after patching
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MaskedRegion {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// Returns sorted, non-overlapping ranges of the input that are covered by mask specs
+pub fn find_mask_ranges(data: &[u8], specs: &[MaskSpec]) -> Vec<MaskRange> {
+    let len = data.len() as u64;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    let mut push_range = |offset: u64, size: u64| {
+        let begin = min(offset, len);
+        let end = min(offset.saturating_add(size), len);
+        if begin != end {
+            ranges.push((begin, end));
+        }
+    };
+    for spec in specs {
+        match spec {
+            MaskSpec::Range { offset, size } => push_range(*offset, *size),
+            MaskSpec::Pattern { pattern, size } => {
+                for (i, window) in data.windows(pattern.len()).enumerate() {
+                    if window == &pattern[..] {
+                        push_range((i + pattern.len()) as u64, *size);
+                    }
+                }
+            }
+        }
+    }
+    ranges.sort_unstable();
+    let mut result: Vec<MaskRange> = Vec::with_capacity(ranges.len());
+    for (begin, end) in ranges {
+        match result.last_mut() {
+            Some(last) if begin <= last.offset + last.size => {
+                last.size = end.max(last.offset + last.size) - last.offset;
+            }
+            _ => result.push(MaskRange {
+                offset: begin,
+                size: end - begin,
+            }),
+        }
+    }
+    result
+}
+
+// Replaces masked bytes with zeros
+pub fn mask_data<'a>(data: &'a [u8], ranges: &[MaskRange]) -> Cow<'a, [u8]> {
+    if ranges.is_empty() {
+        return Cow::Borrowed(data);
+    }
+    let mut result = data.to_vec();
+    for range in ranges {
+        let begin = range.offset as usize;
+        let end = begin + range.size as usize;
+        result[begin..end].fill(0);
+    }
+    Cow::Owned(result)
+}
+
+pub fn extract_masked_regions(data: &[u8], ranges: &[MaskRange]) -> Vec<MaskedRegion> {
+    ranges
+        .iter()
+        .map(|range| {
+            let begin = range.offset as usize;
+            let end = begin + range.size as usize;
+            MaskedRegion {
+                offset: range.offset,
+                data: data[begin..end].to_vec(),
+            }
+        })
+        .collect()
+}
+
+// Parts of masked regions that fall into range [begin, end), with offsets relative to it
+pub fn clip_masked_regions(regions: &[MaskedRegion], begin: u64, end: u64) -> Vec<MaskedRegion> {
+    regions
+        .iter()
+        .filter_map(|region| {
+            let clip_begin = region.offset.max(begin);
+            let clip_end = (region.offset + region.data.len() as u64).min(end);
+            (clip_begin < clip_end).then(|| MaskedRegion {
+                offset: clip_begin - begin,
+                data: region.data
+                    [(clip_begin - region.offset) as usize..(clip_end - region.offset) as usize]
+                    .to_vec(),
+            })
+        })
+        .collect()
+}
+
+pub fn restore_masked_regions(data: &mut [u8], regions: &[MaskedRegion]) {
+    for region in regions {
+        let begin = region.offset as usize;
+        let end = begin + region.data.len();
+        data[begin..end].copy_from_slice(&region.data);
+    }
+}
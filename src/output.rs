@@ -0,0 +1,247 @@
+use std::fs::{File, FileTimes, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// BASE file attributes that are copied to the output file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PreserveMetadata {
+    pub mode: bool,
+    pub ownership: bool,
+    pub timestamps: bool,
+    pub xattrs: bool,
+}
+
+impl PreserveMetadata {
+    pub const NAMES: [&'static str; 5] = ["mode", "ownership", "timestamps", "xattrs", "all"];
+
+    pub fn all() -> Self {
+        Self {
+            mode: true,
+            ownership: true,
+            timestamps: true,
+            xattrs: true,
+        }
+    }
+    // Returns false if the name is unknown
+    pub fn add(&mut self, name: &str) -> bool {
+        match name {
+            "mode" => self.mode = true,
+            "ownership" => self.ownership = true,
+            "timestamps" => self.timestamps = true,
+            "xattrs" => self.xattrs = true,
+            "all" => *self = Self::all(),
+            _ => return false,
+        }
+        true
+    }
+}
+
+// Output file metadata, explicitly set values take precedence over preserved ones
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataOptions {
+    pub preserve: PreserveMetadata,
+    pub mode: Option<u32>,
+    pub owner: Option<(u32, Option<u32>)>,
+    pub mtime: Option<SystemTime>,
+}
+
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    // Returns empty list if the file system does not support extended attributes
+    fn list(file: &File) -> std::io::Result<Vec<CString>> {
+        let fd = file.as_raw_fd();
+        let size = unsafe { libc::flistxattr(fd, std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let e = Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOTSUP) => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+        let mut names: Vec<u8> = vec![0; size as usize];
+        let size = unsafe { libc::flistxattr(fd, names.as_mut_ptr() as *mut _, names.len()) };
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        names.truncate(size as usize);
+        Ok(names
+            .split(|&x| x == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| CString::new(name).unwrap())
+            .collect())
+    }
+
+    fn get(file: &File, name: &CString) -> std::io::Result<Vec<u8>> {
+        let fd = file.as_raw_fd();
+        let size = unsafe { libc::fgetxattr(fd, name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut value: Vec<u8> = vec![0; size as usize];
+        let size = unsafe {
+            libc::fgetxattr(fd, name.as_ptr(), value.as_mut_ptr() as *mut _, value.len())
+        };
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        value.truncate(size as usize);
+        Ok(value)
+    }
+
+    pub fn copy(source: &File, target: &File) -> std::io::Result<()> {
+        for name in list(source)? {
+            let value = get(source, &name)?;
+            let result = unsafe {
+                libc::fsetxattr(
+                    target.as_raw_fd(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const _,
+                    value.len(),
+                    0,
+                )
+            };
+            if result != 0 {
+                let e = Error::last_os_error();
+                return Err(Error::new(
+                    e.kind(),
+                    format!(
+                        "Could not set extended attribute '{}': {}",
+                        name.to_string_lossy(),
+                        e
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sets output file metadata, which must happen after all data is written since writes update
+// modification time
+pub fn apply_metadata(
+    base_file: &File,
+    output_file: &File,
+    options: &MetadataOptions,
+) -> std::io::Result<()> {
+    let base = base_file.metadata()?;
+    let preserve = options.preserve;
+
+    // Ownership goes first, since changing it clears setuid and setgid bits
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let owner = match options.owner {
+            Some((uid, gid)) => Some((Some(uid), gid)),
+            None if preserve.ownership => Some((Some(base.uid()), Some(base.gid()))),
+            None => None,
+        };
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::fchown(output_file, uid, gid)?;
+        }
+    }
+    #[cfg(not(unix))]
+    if options.owner.is_some() || preserve.ownership {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "File ownership is not supported on this platform",
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    if preserve.xattrs {
+        xattr::copy(base_file, output_file)?;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let mode = match options.mode {
+            Some(mode) => Some(mode),
+            None if preserve.mode => Some(base.mode() & 0o7777),
+            None => None,
+        };
+        if let Some(mode) = mode {
+            output_file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    #[cfg(not(unix))]
+    if preserve.mode {
+        output_file.set_permissions(base.permissions())?;
+    }
+
+    let mtime = match options.mtime {
+        Some(mtime) => Some(mtime),
+        None if preserve.timestamps => Some(base.modified()?),
+        None => None,
+    };
+    if let Some(mtime) = mtime {
+        let atime = match preserve.timestamps {
+            true => base.accessed()?,
+            false => mtime,
+        };
+        output_file.set_times(FileTimes::new().set_modified(mtime).set_accessed(atime))?;
+    }
+    Ok(())
+}
+
+// Output is written to a new file next to the destination first and renamed over it once it is
+// complete. The name is unique, so that existing files and concurrent patches are never touched.
+pub fn create_output_temp(path: &Path) -> std::io::Result<(File, PathBuf)> {
+    let mut attempt: u32 = 0;
+    loop {
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(format!(".tmp{}", std::process::id()));
+        if attempt > 0 {
+            temp_path.push(format!("-{}", attempt));
+        }
+        let temp_path = PathBuf::from(temp_path);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((file, temp_path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Syncs the output file and atomically replaces the destination with it. The directory is
+// synced as well, so that the rename itself survives a crash.
+pub fn commit_output(output_file: File, temp_path: &Path, path: &Path) -> std::io::Result<()> {
+    output_file.sync_all()?;
+    drop(output_file);
+    std::fs::rename(temp_path, path)?;
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Returns true if both paths exist and refer to the same file
+pub fn is_same_file(a: &Path, b: &Path) -> std::io::Result<bool> {
+    if !a.exists() || !b.exists() {
+        return Ok(false);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let (a, b) = (std::fs::metadata(a)?, std::fs::metadata(b)?);
+        Ok(a.dev() == b.dev() && a.ino() == b.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(std::fs::canonicalize(a)? == std::fs::canonicalize(b)?)
+    }
+}
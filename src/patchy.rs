@@ -1,19 +1,27 @@
 use crate::hash::*;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet};
 
 pub const DEFAULT_BLOCK_SIZE: usize = 2048;
-
-fn div_up(num: usize, den: usize) -> usize {
-    (num + den - 1) / den
-}
+pub const MIN_FILL_SIZE: usize = 32;
+// Size of output regions that are patched in parallel
+pub const APPLY_REGION_SIZE: usize = 1 << 22;
+// Adaptive block size is chosen for regions of at least this size
+pub const MIN_LAYOUT_REGION_SIZE: usize = 1 << 16;
+// Entropy (bits per byte) below which the smallest adaptive block size is used
+const LOW_ENTROPY: f64 = 4.0;
+// Entropy above which the largest adaptive block size is used
+const HIGH_ENTROPY: f64 = 7.5;
+// BASE is indexed instead of OTHER when it is at least this many times larger
+pub const REVERSE_INDEX_SIZE_RATIO: usize = 4;
 
 fn slice_offset_from(slice: &[u8], base: &[u8]) -> u64 {
     slice.as_ptr() as u64 - base.as_ptr() as u64
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Block {
     pub offset: u64,
     pub size: u32,
@@ -21,16 +29,114 @@ pub struct Block {
     pub hash_strong: Hash128,
 }
 
+fn byte_entropy(data: &[u8]) -> f64 {
+    let mut histogram = [0u32; 256];
+    for &x in data {
+        histogram[x as usize] += 1;
+    }
+    let len = data.len() as f64;
+    histogram
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Sizes of blocks that OTHER file is split into. Adaptive layout uses small blocks for
+// structured (low entropy) regions that tend to change in small ways and large blocks for
+// high entropy regions such as compressed media.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum BlockLayout {
+    Fixed {
+        block_size: u32,
+    },
+    Adaptive {
+        region_size: u32,
+        // log2 of the block size used in each region
+        block_sizes_log2: Vec<u8>,
+    },
+}
+
+impl BlockLayout {
+    // Block size bounds are rounded up to powers of two, layout is fixed if they are equal
+    pub fn new(input: &[u8], block_size_bounds: (usize, usize)) -> Self {
+        let fit_block_size = |size: usize| size.clamp(1, 1 << 31).next_power_of_two();
+        let min_block_size = fit_block_size(block_size_bounds.0);
+        let max_block_size = fit_block_size(block_size_bounds.1);
+        if min_block_size >= max_block_size {
+            return BlockLayout::Fixed {
+                block_size: min_block_size as u32,
+            };
+        }
+        let region_size = max_block_size.max(MIN_LAYOUT_REGION_SIZE);
+        let min_log2 = min_block_size.trailing_zeros() as f64;
+        let max_log2 = max_block_size.trailing_zeros() as f64;
+        let block_sizes_log2: Vec<u8> = input
+            .par_chunks(region_size)
+            .map(|region| {
+                let t = (byte_entropy(region) - LOW_ENTROPY) / (HIGH_ENTROPY - LOW_ENTROPY);
+                let t = t.clamp(0.0, 1.0);
+                (min_log2 + (max_log2 - min_log2) * t).round() as u8
+            })
+            .collect();
+        BlockLayout::Adaptive {
+            region_size: region_size as u32,
+            block_sizes_log2,
+        }
+    }
+    pub fn block_size_at(&self, offset: u64) -> usize {
+        match self {
+            BlockLayout::Fixed { block_size } => *block_size as usize,
+            BlockLayout::Adaptive {
+                region_size,
+                block_sizes_log2,
+            } => 1 << block_sizes_log2[(offset / *region_size as u64) as usize],
+        }
+    }
+    // Distinct block sizes in ascending order
+    pub fn block_sizes(&self) -> Vec<usize> {
+        match self {
+            BlockLayout::Fixed { block_size } => vec![*block_size as usize],
+            BlockLayout::Adaptive {
+                block_sizes_log2, ..
+            } => {
+                let mut result: Vec<usize> = block_sizes_log2.iter().map(|&x| 1 << x).collect();
+                result.sort_unstable();
+                result.dedup();
+                result
+            }
+        }
+    }
+}
+
 pub fn compute_blocks(input: &[u8], block_size: usize) -> Vec<Block> {
-    let chunks = input.chunks(block_size);
-    let mut result: Vec<Block> = Vec::with_capacity(chunks.len());
-    for chunk in chunks {
-        result.push(Block {
-            offset: slice_offset_from(chunk, input),
-            size: chunk.len() as u32,
-            hash_weak: 0,
-            hash_strong: Hash128::new_zero(),
-        });
+    compute_blocks_with_layout(
+        input,
+        &BlockLayout::Fixed {
+            block_size: block_size as u32,
+        },
+    )
+}
+
+pub fn compute_blocks_with_layout(input: &[u8], layout: &BlockLayout) -> Vec<Block> {
+    let region_size = match layout {
+        BlockLayout::Fixed { block_size } => *block_size as usize,
+        BlockLayout::Adaptive { region_size, .. } => *region_size as usize,
+    };
+    let mut result: Vec<Block> = Vec::new();
+    for region in input.chunks(region_size) {
+        let block_size = layout.block_size_at(slice_offset_from(region, input));
+        for chunk in region.chunks(block_size) {
+            result.push(Block {
+                offset: slice_offset_from(chunk, input),
+                size: chunk.len() as u32,
+                hash_weak: 0,
+                hash_strong: Hash128::new_zero(),
+            });
+        }
     }
     result.par_iter_mut().for_each(|block| {
         let block_begin = block.offset as usize;
@@ -42,7 +148,7 @@ pub fn compute_blocks(input: &[u8], block_size: usize) -> Vec<Block> {
     result
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CopyCmd {
     pub source: u64,
     pub target: u64,
@@ -63,11 +169,59 @@ impl CopyCmd {
         let target_slice = target[target_bounds.0..target_bounds.1].as_mut();
         target_slice.copy_from_slice(source_slice);
     }
+    // Executes the part of the command that falls into output region starting at region_offset
+    pub fn execute_region(&self, region: &mut [u8], region_offset: u64, source: &[u8]) {
+        let begin = max(self.target, region_offset);
+        let end = min(
+            self.target + self.size as u64,
+            region_offset + region.len() as u64,
+        );
+        if begin < end {
+            let source_begin = (self.source + (begin - self.target)) as usize;
+            let source_end = source_begin + (end - begin) as usize;
+            let target_begin = (begin - region_offset) as usize;
+            let target_end = (end - region_offset) as usize;
+            region[target_begin..target_end].copy_from_slice(&source[source_begin..source_end]);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FillCmd {
+    pub target: u64,
+    pub size: u32,
+    pub byte: u8,
+}
+
+impl FillCmd {
+    pub fn execute(&self, target: &mut [u8]) {
+        let target_bounds = (
+            self.target as usize,
+            self.target as usize + self.size as usize,
+        );
+        target[target_bounds.0..target_bounds.1].fill(self.byte);
+    }
+    pub fn execute_region(&self, region: &mut [u8], region_offset: u64) {
+        let begin = max(self.target, region_offset);
+        let end = min(
+            self.target + self.size as u64,
+            region_offset + region.len() as u64,
+        );
+        if begin < end {
+            region[(begin - region_offset) as usize..(end - region_offset) as usize]
+                .fill(self.byte);
+        }
+    }
 }
 
 pub struct PatchCommands {
     pub base: Vec<CopyCmd>,
     pub other: Vec<CopyCmd>,
+    pub align: usize,
+    pub prefix: usize,
+    pub suffix: usize,
+    // Bytes copied from BASE that were found only by overlapping signatures
+    pub recovered: usize,
 }
 
 fn compute_copy_size(cmds: &[CopyCmd]) -> usize {
@@ -78,13 +232,30 @@ fn compute_copy_size(cmds: &[CopyCmd]) -> usize {
     result
 }
 
+impl Default for PatchCommands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PatchCommands {
     pub fn new() -> Self {
         Self {
             base: Vec::new(),
             other: Vec::new(),
+            align: 1,
+            prefix: 0,
+            suffix: 0,
+            recovered: 0,
         }
     }
+    // Commands that copy the whole input from base
+    pub fn new_copy_base(size: usize, align: usize) -> Self {
+        let mut result = Self::new();
+        result.align = align;
+        push_copy_cmds(&mut result.base, 0, 0, size);
+        result
+    }
     pub fn need_bytes_from_base(&self) -> usize {
         compute_copy_size(&self.base)
     }
@@ -109,14 +280,23 @@ fn is_synchronized(sequence: &[Hash128], blocks: &[Block]) -> bool {
 }
 
 pub fn compute_diff(input: &[u8], other_blocks: &[Block], block_size: usize) -> PatchCommands {
+    compute_diff_aligned(input, other_blocks, block_size, 1)
+}
+
+// Scans input for windows that match any of the given blocks of `block_size` bytes (last block
+// may be shorter) and records offsets of matching windows. Returns the sequence of matches.
+fn find_base_blocks<'a>(
+    input: &[u8],
+    blocks: impl Iterator<Item = &'a Block>,
+    block_size: usize,
+    align: usize,
+    base_block_hash_map: &mut HashMap<Hash128, u64>,
+) -> Vec<Hash128> {
     let mut other_block_weak_set: HashSet<u32> = HashSet::new();
     let mut other_block_strong_set: HashSet<Hash128> = HashSet::new();
-    let mut base_block_hash_map: HashMap<Hash128, u64> = HashMap::new();
-    let mut other_len = 0;
-    for block in other_blocks {
+    for block in blocks {
         other_block_weak_set.insert(block.hash_weak);
         other_block_strong_set.insert(block.hash_strong);
-        other_len += block.size as usize;
     }
     let find_base_block =
         |block_begin: usize, block_end: usize, block_hash_weak: u32| -> Option<Block> {
@@ -138,8 +318,7 @@ pub fn compute_diff(input: &[u8], other_blocks: &[Block], block_size: usize) ->
     let mut rolling_hash = RollingHash::new();
     let mut window_begin: usize = 0;
     let mut window_end: usize = window_begin;
-    let mut sequence: Vec<Hash128> = Vec::new();
-    sequence.reserve(div_up(input.len(), block_size));
+    let mut sequence: Vec<Hash128> = Vec::with_capacity(input.len().div_ceil(block_size));
     loop {
         let remaining_len = input.len() - window_begin;
         if remaining_len == 0 {
@@ -150,7 +329,12 @@ pub fn compute_diff(input: &[u8], other_blocks: &[Block], block_size: usize) ->
             rolling_hash.add(input[window_end]);
             window_end += 1;
         }
-        match find_base_block(window_begin, window_end, rolling_hash.get()) {
+        let base_block = if window_begin.is_multiple_of(align) {
+            find_base_block(window_begin, window_end, rolling_hash.get())
+        } else {
+            None
+        };
+        match base_block {
             Some(base_block) => {
                 window_begin = window_end;
                 rolling_hash = RollingHash::new();
@@ -163,27 +347,667 @@ pub fn compute_diff(input: &[u8], other_blocks: &[Block], block_size: usize) ->
             }
         }
     }
+    sequence
+}
+
+// Scans input for windows that match any of the blocks, which must all be `block_size` bytes,
+// and returns input offsets of the matches by strong hash
+pub fn find_blocks(input: &[u8], blocks: &[Block], block_size: usize) -> HashMap<Hash128, u64> {
+    let mut result: HashMap<Hash128, u64> = HashMap::new();
+    find_base_blocks(input, blocks.iter(), block_size, 1, &mut result);
+    result
+}
+
+fn build_copy_cmds(
+    other_blocks: &[Block],
+    base_block_hash_map: &HashMap<Hash128, u64>,
+    align: usize,
+) -> PatchCommands {
     let mut patch_commands = PatchCommands::new();
-    if input.len() != other_len || !is_synchronized(&sequence, &other_blocks) {
-        for other_block in other_blocks {
-            match base_block_hash_map.get(&other_block.hash_strong) {
-                Some(&base_offset) => {
+    patch_commands.align = align;
+    for other_block in other_blocks {
+        match base_block_hash_map.get(&other_block.hash_strong) {
+            Some(&base_offset) => {
+                patch_commands.base.push(CopyCmd {
+                    source: base_offset,
+                    target: other_block.offset,
+                    size: other_block.size,
+                });
+            }
+            None => {
+                patch_commands.other.push(CopyCmd {
+                    source: other_block.offset,
+                    target: other_block.offset,
+                    size: other_block.size,
+                });
+            }
+        }
+    }
+    patch_commands
+}
+
+// Largest alignment up to `align` that divides all block sizes, so that copy targets of blocks
+// are aligned as well. Zero alignment is the same as no alignment.
+fn fit_align(align: usize, block_sizes: &[usize]) -> usize {
+    let gcd = |mut a: usize, mut b: usize| {
+        while b != 0 {
+            let r = a % b;
+            a = b;
+            b = r;
+        }
+        a
+    };
+    block_sizes
+        .iter()
+        .fold(align.max(1), |align, &size| gcd(align, size))
+}
+
+// Same as compute_diff, but only uses base blocks that start at a multiple of `align` bytes.
+// Alignment is reduced to divide block size if necessary, so that all copy targets are aligned
+// as well, and returned commands record the alignment that was used.
+pub fn compute_diff_aligned(
+    input: &[u8],
+    other_blocks: &[Block],
+    block_size: usize,
+    align: usize,
+) -> PatchCommands {
+    let align = fit_align(align, &[block_size]);
+    let mut base_block_hash_map: HashMap<Hash128, u64> = HashMap::new();
+    let other_len: usize = other_blocks.iter().map(|block| block.size as usize).sum();
+    let sequence = find_base_blocks(
+        input,
+        other_blocks.iter(),
+        block_size,
+        align,
+        &mut base_block_hash_map,
+    );
+    if input.len() != other_len || !is_synchronized(&sequence, other_blocks) {
+        build_copy_cmds(other_blocks, &base_block_hash_map, align)
+    } else {
+        let mut patch_commands = PatchCommands::new();
+        patch_commands.align = align;
+        patch_commands
+    }
+}
+
+// Extra OTHER windows that start between block boundaries, `density` windows per block.
+// Matching them recovers data that is present in BASE but straddles block boundaries.
+pub fn compute_overlapping_blocks(
+    input: &[u8],
+    layout: &BlockLayout,
+    density: usize,
+    align: usize,
+) -> Vec<Block> {
+    let align = align.max(1);
+    let mut result: Vec<Block> = Vec::new();
+    if density > 1 {
+        for block in compute_blocks_with_layout(input, layout) {
+            let block_size = layout.block_size_at(block.offset);
+            let stride = block_size / density / align * align;
+            if stride == 0 {
+                continue;
+            }
+            let window_begins = (block.offset as usize + stride..)
+                .step_by(stride)
+                .take_while(|&offset| offset < block.offset as usize + block_size)
+                .take_while(|&offset| offset + block_size <= input.len());
+            for offset in window_begins {
+                result.push(Block {
+                    offset: offset as u64,
+                    size: block_size as u32,
+                    hash_weak: 0,
+                    hash_strong: Hash128::new_zero(),
+                });
+            }
+        }
+    }
+    result.par_iter_mut().for_each(|block| {
+        let block_begin = block.offset as usize;
+        let block_end = block_begin + block.size as usize;
+        let block_slice = &input[block_begin..block_end];
+        block.hash_weak = compute_hash_weak(block_slice);
+        block.hash_strong = compute_hash_strong(block_slice);
+    });
+    result
+}
+
+fn find_base_blocks_with_layout(
+    input: &[u8],
+    blocks: &[Block],
+    layout: &BlockLayout,
+    align: usize,
+    base_block_hash_map: &mut HashMap<Hash128, u64>,
+) {
+    for block_size in layout.block_sizes() {
+        let blocks = blocks
+            .iter()
+            .filter(|block| layout.block_size_at(block.offset) == block_size);
+        find_base_blocks(input, blocks, block_size, align, base_block_hash_map);
+    }
+}
+
+// Replaces parts of literal copies that are covered by matched overlapping windows with
+// copies from BASE
+fn recover_overlapping_blocks(
+    patch_commands: &mut PatchCommands,
+    windows: &[Block],
+    base_block_hash_map: &HashMap<Hash128, u64>,
+) {
+    // Matched windows as (target begin, target end, source begin), sorted by target
+    let mut matches: Vec<(u64, u64, u64)> = windows
+        .iter()
+        .filter_map(|window| {
+            let source = *base_block_hash_map.get(&window.hash_strong)?;
+            Some((window.offset, window.offset + window.size as u64, source))
+        })
+        .collect();
+    matches.sort_unstable();
+    // Index of the window with the furthest end among windows that begin at or before each one
+    let mut furthest: Vec<usize> = Vec::with_capacity(matches.len());
+    for (i, m) in matches.iter().enumerate() {
+        match furthest.last() {
+            Some(&prev) if matches[prev].1 >= m.1 => furthest.push(prev),
+            _ => furthest.push(i),
+        }
+    }
+    let mut other: Vec<CopyCmd> = Vec::with_capacity(patch_commands.other.len());
+    for cmd in &patch_commands.other {
+        let end = cmd.target + cmd.size as u64;
+        let mut pos = cmd.target;
+        while pos < end {
+            let count = matches.partition_point(|m| m.0 <= pos);
+            let covering = count.checked_sub(1).map(|i| matches[furthest[i]]);
+            match covering {
+                Some((begin, window_end, source)) if window_end > pos => {
+                    let copy_end = min(window_end, end);
                     patch_commands.base.push(CopyCmd {
-                        source: base_offset,
-                        target: other_block.offset,
-                        size: other_block.size,
+                        source: source + (pos - begin),
+                        target: pos,
+                        size: (copy_end - pos) as u32,
                     });
+                    patch_commands.recovered += (copy_end - pos) as usize;
+                    pos = copy_end;
                 }
-                None => {
-                    patch_commands.other.push(CopyCmd {
-                        source: other_block.offset,
-                        target: other_block.offset,
-                        size: other_block.size,
+                _ => {
+                    let literal_end = matches.get(count).map_or(end, |m| min(m.0, end));
+                    other.push(CopyCmd {
+                        source: pos,
+                        target: pos,
+                        size: (literal_end - pos) as u32,
                     });
+                    pos = literal_end;
+                }
+            }
+        }
+    }
+    patch_commands.other = other;
+}
+
+// Input is scanned once for every distinct block size of the layout and once more for
+// overlapping windows that intersect OTHER blocks that were not found
+pub fn compute_diff_with_layout(
+    input: &[u8],
+    other_blocks: &[Block],
+    overlapping_blocks: &[Block],
+    layout: &BlockLayout,
+    align: usize,
+) -> PatchCommands {
+    let block_sizes = layout.block_sizes();
+    let align = fit_align(align, &block_sizes);
+    let mut patch_commands = match block_sizes[..] {
+        [block_size] => compute_diff_aligned(input, other_blocks, block_size, align),
+        _ => {
+            let mut base_block_hash_map: HashMap<Hash128, u64> = HashMap::new();
+            find_base_blocks_with_layout(
+                input,
+                other_blocks,
+                layout,
+                align,
+                &mut base_block_hash_map,
+            );
+            let other_len: usize = other_blocks.iter().map(|block| block.size as usize).sum();
+            let synchronized = input.len() == other_len
+                && other_blocks.iter().all(|block| {
+                    base_block_hash_map.get(&block.hash_strong) == Some(&block.offset)
+                });
+            if synchronized {
+                let mut patch_commands = PatchCommands::new();
+                patch_commands.align = align;
+                patch_commands
+            } else {
+                build_copy_cmds(other_blocks, &base_block_hash_map, align)
+            }
+        }
+    };
+    if !patch_commands.other.is_empty() && !overlapping_blocks.is_empty() {
+        let intersects_literal = |window: &Block| {
+            let window_end = window.offset + window.size as u64;
+            let count = patch_commands
+                .other
+                .partition_point(|cmd| cmd.target < window_end);
+            count > 0 && {
+                let cmd = &patch_commands.other[count - 1];
+                cmd.target + cmd.size as u64 > window.offset
+            }
+        };
+        let windows: Vec<Block> = overlapping_blocks
+            .iter()
+            .filter(|window| intersects_literal(window))
+            .cloned()
+            .collect();
+        let mut base_block_hash_map: HashMap<Hash128, u64> = HashMap::new();
+        find_base_blocks_with_layout(input, &windows, layout, align, &mut base_block_hash_map);
+        recover_overlapping_blocks(&mut patch_commands, &windows, &base_block_hash_map);
+    }
+    patch_commands
+}
+
+fn common_prefix_len<'a>(
+    a: impl Iterator<Item = &'a [u8]>,
+    b: impl Iterator<Item = &'a [u8]>,
+    reverse: bool,
+) -> usize {
+    let mut result: usize = 0;
+    for (a_chunk, b_chunk) in a.zip(b) {
+        if a_chunk == b_chunk {
+            result += a_chunk.len();
+            continue;
+        }
+        result += if reverse {
+            let pairs = a_chunk.iter().rev().zip(b_chunk.iter().rev());
+            pairs.take_while(|(x, y)| x == y).count()
+        } else {
+            let pairs = a_chunk.iter().zip(b_chunk.iter());
+            pairs.take_while(|(x, y)| x == y).count()
+        };
+        break;
+    }
+    result
+}
+
+// Finds lengths of the longest common prefix and suffix of two inputs by direct comparison.
+// Results are rounded down so that the prefix and suffix copies are aligned to `align` bytes.
+pub fn find_common_prefix_suffix(base: &[u8], other: &[u8], align: usize) -> (usize, usize) {
+    const CHUNK_SIZE: usize = 1 << 16;
+    let align = align.max(1);
+    let max_len = min(base.len(), other.len());
+    let prefix = common_prefix_len(
+        base[..max_len].chunks(CHUNK_SIZE),
+        other[..max_len].chunks(CHUNK_SIZE),
+        false,
+    );
+    let prefix = prefix / align * align;
+    let suffix = common_prefix_len(
+        base[prefix..].rchunks(CHUNK_SIZE),
+        other[prefix..].rchunks(CHUNK_SIZE),
+        true,
+    );
+    let other_suffix_begin = (other.len() - suffix).next_multiple_of(align);
+    let suffix = other.len().saturating_sub(other_suffix_begin);
+    if (base.len() - suffix).is_multiple_of(align) {
+        (prefix, suffix)
+    } else {
+        (prefix, 0)
+    }
+}
+
+fn push_copy_cmds(cmds: &mut Vec<CopyCmd>, source: usize, target: usize, size: usize) {
+    const MAX_COPY_SIZE: usize = 1 << 31;
+    let mut offset: usize = 0;
+    while offset < size {
+        let copy_size = min(size - offset, MAX_COPY_SIZE);
+        cmds.push(CopyCmd {
+            source: (source + offset) as u64,
+            target: (target + offset) as u64,
+            size: copy_size as u32,
+        });
+        offset += copy_size;
+    }
+}
+
+// Alternative to compute_diff that indexes BASE blocks and rolls over OTHER, which is faster
+// when BASE is much larger than OTHER. Matches are extended past block boundaries, so copies
+// from BASE start and end at exact (aligned) target offsets.
+pub fn compute_diff_reverse(
+    base: &[u8],
+    other: &[u8],
+    block_size: usize,
+    align: usize,
+) -> PatchCommands {
+    let align = fit_align(align, &[block_size]);
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.align = align;
+    let mut base_block_map: HashMap<u32, Vec<&Block>> = HashMap::new();
+    let base_blocks = compute_blocks(base, block_size);
+    for block in base_blocks.iter().filter(|b| b.size as usize == block_size) {
+        base_block_map
+            .entry(block.hash_weak)
+            .or_default()
+            .push(block);
+    }
+    let find_base_block = |window: &[u8], hash_weak: u32| -> Option<usize> {
+        let candidates = base_block_map.get(&hash_weak)?;
+        let hash_strong = compute_hash_strong(window);
+        let block = candidates.iter().find(|b| b.hash_strong == hash_strong)?;
+        Some(block.offset as usize)
+    };
+    let chunk_eq = |source: usize, target: usize| {
+        base[source..source + align] == other[target..target + align]
+    };
+    let mut rolling_hash = RollingHash::new();
+    let mut literal_begin: usize = 0;
+    let mut window_begin: usize = 0;
+    let mut window_end: usize = 0;
+    while window_begin + block_size <= other.len() {
+        while rolling_hash.count() < block_size {
+            rolling_hash.add(other[window_end]);
+            window_end += 1;
+        }
+        let base_offset = if window_begin.is_multiple_of(align) {
+            find_base_block(&other[window_begin..window_end], rolling_hash.get())
+        } else {
+            None
+        };
+        match base_offset {
+            Some(base_offset) => {
+                let (mut source, mut target) = (base_offset, window_begin);
+                while target >= literal_begin + align
+                    && source >= align
+                    && chunk_eq(source - align, target - align)
+                {
+                    source -= align;
+                    target -= align;
                 }
+                let (mut source_end, mut target_end) = (base_offset + block_size, window_end);
+                while target_end + align <= other.len()
+                    && source_end + align <= base.len()
+                    && chunk_eq(source_end, target_end)
+                {
+                    source_end += align;
+                    target_end += align;
+                }
+                let literal_size = target - literal_begin;
+                push_copy_cmds(
+                    &mut patch_commands.other,
+                    literal_begin,
+                    literal_begin,
+                    literal_size,
+                );
+                push_copy_cmds(
+                    &mut patch_commands.base,
+                    source,
+                    target,
+                    target_end - target,
+                );
+                literal_begin = target_end;
+                window_begin = target_end;
+                window_end = target_end;
+                rolling_hash = RollingHash::new();
+            }
+            None => {
+                rolling_hash.sub(other[window_begin]);
+                window_begin += 1;
             }
         }
     }
+    let literal_size = other.len() - literal_begin;
+    push_copy_cmds(
+        &mut patch_commands.other,
+        literal_begin,
+        literal_begin,
+        literal_size,
+    );
+    let identical = base.len() == other.len()
+        && patch_commands.other.is_empty()
+        && patch_commands
+            .base
+            .iter()
+            .all(|cmd| cmd.source == cmd.target);
+    if identical {
+        patch_commands.base.clear();
+    }
+    patch_commands
+}
+
+// Which input is indexed by block hashes and which one is scanned with a rolling hash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffDirection {
+    // Reverse if BASE is much larger than OTHER
+    Auto,
+    // Index OTHER and scan BASE
+    Forward,
+    // Index BASE and scan OTHER
+    Reverse,
+}
+
+impl DiffDirection {
+    pub const NAMES: [&'static str; 3] = ["auto", "forward", "reverse"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(DiffDirection::Auto),
+            "forward" => Some(DiffDirection::Forward),
+            "reverse" => Some(DiffDirection::Reverse),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            DiffDirection::Auto => "auto",
+            DiffDirection::Forward => "forward",
+            DiffDirection::Reverse => "reverse",
+        }
+    }
+    pub fn resolve(&self, base_len: usize, other_len: usize) -> Self {
+        match self {
+            DiffDirection::Auto
+                if base_len >= other_len.saturating_mul(REVERSE_INDEX_SIZE_RATIO) =>
+            {
+                DiffDirection::Reverse
+            }
+            DiffDirection::Auto => DiffDirection::Forward,
+            _ => *self,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DiffParams {
+    // Adaptive block size is used if the bounds are different
+    pub block_size_bounds: (usize, usize),
+    // Number of overlapping OTHER signatures per block
+    pub density: usize,
+    pub align: usize,
+    pub direction: DiffDirection,
+}
+
+impl DiffParams {
+    pub fn new(block_size: usize, align: usize) -> Self {
+        Self {
+            block_size_bounds: (block_size, block_size),
+            density: 1,
+            align,
+            direction: DiffDirection::Auto,
+        }
+    }
+}
+
+// Copies common prefix and suffix of the inputs directly from base and only runs block matching
+// on the remaining middle part, which makes patches for files that grow at the end very fast.
+pub fn compute_diff_trimmed(
+    base: &[u8],
+    other: &[u8],
+    block_size: usize,
+    align: usize,
+) -> PatchCommands {
+    compute_diff_trimmed_with(base, other, &DiffParams::new(block_size, align)).0
+}
+
+// Returns patch commands and the block layout of the part of OTHER between common prefix and
+// suffix. Reverse direction always uses the smallest block size and no overlapping signatures.
+pub fn compute_diff_trimmed_with(
+    base: &[u8],
+    other: &[u8],
+    params: &DiffParams,
+) -> (PatchCommands, BlockLayout) {
+    let (block_size_bounds, align) = (params.block_size_bounds, params.align);
+    let (prefix, suffix) = find_common_prefix_suffix(base, other, align);
+    if base.len() == other.len() && prefix + suffix == base.len() {
+        let layout = BlockLayout::new(&[], block_size_bounds);
+        return (PatchCommands::new(), layout);
+    }
+    let base_middle = &base[prefix..base.len() - suffix];
+    let other_middle = &other[prefix..other.len() - suffix];
+    let (middle_commands, layout) = match params.direction.resolve(base.len(), other.len()) {
+        DiffDirection::Reverse => {
+            let block_size = block_size_bounds.0;
+            let commands = compute_diff_reverse(base_middle, other_middle, block_size, align);
+            let layout = BlockLayout::Fixed {
+                block_size: block_size as u32,
+            };
+            (commands, layout)
+        }
+        _ => {
+            let layout = BlockLayout::new(other_middle, block_size_bounds);
+            let other_blocks = compute_blocks_with_layout(other_middle, &layout);
+            let overlapping_blocks =
+                compute_overlapping_blocks(other_middle, &layout, params.density, align);
+            let commands = compute_diff_with_layout(
+                base_middle,
+                &other_blocks,
+                &overlapping_blocks,
+                &layout,
+                align,
+            );
+            (commands, layout)
+        }
+    };
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.align = middle_commands.align;
+    patch_commands.prefix = prefix;
+    patch_commands.suffix = suffix;
+    patch_commands.recovered = middle_commands.recovered;
+    if middle_commands.is_synchronized() && !other_middle.is_empty() {
+        push_copy_cmds(&mut patch_commands.base, prefix, prefix, other_middle.len());
+    }
+    for cmd in &middle_commands.base {
+        push_copy_cmds(
+            &mut patch_commands.base,
+            prefix + cmd.source as usize,
+            prefix + cmd.target as usize,
+            cmd.size as usize,
+        );
+    }
+    for cmd in &middle_commands.other {
+        push_copy_cmds(
+            &mut patch_commands.other,
+            prefix + cmd.source as usize,
+            prefix + cmd.target as usize,
+            cmd.size as usize,
+        );
+    }
+    push_copy_cmds(&mut patch_commands.base, 0, 0, prefix);
+    push_copy_cmds(
+        &mut patch_commands.base,
+        base.len() - suffix,
+        other.len() - suffix,
+        suffix,
+    );
+    (patch_commands, layout)
+}
+
+// Block hashes of OTHER that can be computed once and reused to diff it against many BASE files
+#[derive(Serialize, Deserialize)]
+pub struct Signature {
+    pub layout: BlockLayout,
+    pub blocks: Vec<Block>,
+    pub overlapping_blocks: Vec<Block>,
+}
+
+impl Signature {
+    pub fn new(other: &[u8], params: &DiffParams) -> Self {
+        let layout = BlockLayout::new(other, params.block_size_bounds);
+        let blocks = compute_blocks_with_layout(other, &layout);
+        let overlapping_blocks =
+            compute_overlapping_blocks(other, &layout, params.density, params.align);
+        Self {
+            layout,
+            blocks,
+            overlapping_blocks,
+        }
+    }
+}
+
+// Same as forward direction of compute_diff_trimmed_with, but uses a precomputed OTHER signature.
+// Blocks that are entirely inside common prefix or suffix are copied without matching.
+pub fn compute_diff_with_signature(
+    base: &[u8],
+    other: &[u8],
+    signature: &Signature,
+    align: usize,
+) -> PatchCommands {
+    let (prefix, suffix) = find_common_prefix_suffix(base, other, align);
+    if base.len() == other.len() && prefix + suffix == base.len() {
+        return PatchCommands::new();
+    }
+    let suffix_begin = other.len() - suffix;
+    let blocks = &signature.blocks;
+    let first = blocks.partition_point(|b| b.offset + b.size as u64 <= prefix as u64);
+    let last = blocks.partition_point(|b| b.offset < suffix_begin as u64);
+    let middle_blocks = &blocks[first..last.max(first)];
+    let (middle_begin, middle_end) = match (middle_blocks.first(), middle_blocks.last()) {
+        (Some(first), Some(last)) => (
+            first.offset as usize,
+            (last.offset + last.size as u64) as usize,
+        ),
+        _ => (prefix, suffix_begin),
+    };
+    let base_begin = middle_begin;
+    let base_end = base.len() - (other.len() - middle_end);
+    let overlapping_blocks: Vec<Block> = signature
+        .overlapping_blocks
+        .iter()
+        .filter(|b| {
+            b.offset >= middle_begin as u64 && b.offset + b.size as u64 <= middle_end as u64
+        })
+        .cloned()
+        .collect();
+    let middle_commands = compute_diff_with_layout(
+        &base[base_begin..base_end],
+        middle_blocks,
+        &overlapping_blocks,
+        &signature.layout,
+        align,
+    );
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.align = middle_commands.align;
+    patch_commands.prefix = middle_begin;
+    patch_commands.suffix = other.len() - middle_end;
+    patch_commands.recovered = middle_commands.recovered;
+    if middle_commands.is_synchronized() {
+        push_copy_cmds(
+            &mut patch_commands.base,
+            base_begin,
+            middle_begin,
+            middle_end - middle_begin,
+        );
+    }
+    for cmd in &middle_commands.base {
+        push_copy_cmds(
+            &mut patch_commands.base,
+            base_begin + cmd.source as usize,
+            cmd.target as usize,
+            cmd.size as usize,
+        );
+    }
+    patch_commands.other = middle_commands.other;
+    push_copy_cmds(&mut patch_commands.base, 0, 0, middle_begin);
+    push_copy_cmds(
+        &mut patch_commands.base,
+        base_end,
+        middle_end,
+        other.len() - middle_end,
+    );
     patch_commands
 }
 
@@ -192,9 +1016,16 @@ pub struct Patch {
     pub data: Vec<u8>,
     pub base: Vec<CopyCmd>,
     pub other: Vec<CopyCmd>,
+    pub fill: Vec<FillCmd>,
     pub other_size: u64,
 }
 
+impl Patch {
+    pub fn fill_bytes(&self) -> usize {
+        self.fill.iter().map(|cmd| cmd.size as usize).sum()
+    }
+}
+
 fn optimize_copy_cmds(cmds: &mut Vec<CopyCmd>) {
     if cmds.len() > 1 {
         cmds.sort_by_key(|v| v.target);
@@ -202,7 +1033,7 @@ fn optimize_copy_cmds(cmds: &mut Vec<CopyCmd>) {
         for curr in rest.iter_mut() {
             if prev.source + prev.size as u64 == curr.source
                 && prev.target + prev.size as u64 == curr.target
-                && prev.size as u64 + curr.size as u64 <= u32::max_value() as u64
+                && prev.size as u64 + curr.size as u64 <= u32::MAX as u64
             {
                 curr.source = prev.source;
                 curr.target = prev.target;
@@ -215,44 +1046,267 @@ fn optimize_copy_cmds(cmds: &mut Vec<CopyCmd>) {
     }
 }
 
+fn optimize_fill_cmds(cmds: &mut Vec<FillCmd>) {
+    if cmds.len() > 1 {
+        cmds.sort_by_key(|v| v.target);
+        let (mut prev, rest) = cmds.split_first_mut().unwrap();
+        for curr in rest.iter_mut() {
+            if prev.byte == curr.byte
+                && prev.target + prev.size as u64 == curr.target
+                && prev.size as u64 + curr.size as u64 <= u32::MAX as u64
+            {
+                curr.target = prev.target;
+                curr.size += prev.size;
+                prev.size = 0;
+            }
+            prev = curr;
+        }
+        cmds.retain(|cmd| cmd.size != 0);
+    }
+}
+
+// Splits a slice into literal ranges and runs of a single byte value that are long enough
+// to be encoded as fill commands. Returned ranges are relative to the slice and their target
+// offsets are aligned to `align` bytes, except for the end of the slice.
+fn find_fill_runs(slice: &[u8], target: usize, align: usize) -> Vec<(usize, usize)> {
+    let mut result: Vec<(usize, usize)> = Vec::new();
+    let mut run_begin: usize = 0;
+    while run_begin < slice.len() {
+        let byte = slice[run_begin];
+        let run_len = slice[run_begin..]
+            .iter()
+            .position(|&x| x != byte)
+            .unwrap_or(slice.len() - run_begin);
+        let aligned_begin = (target + run_begin).next_multiple_of(align) - target;
+        let aligned_end = match run_begin + run_len {
+            run_end if run_end == slice.len() => run_end,
+            run_end => (target + run_end) / align * align - target,
+        };
+        if aligned_end >= aligned_begin + MIN_FILL_SIZE {
+            result.push((aligned_begin, aligned_end));
+        }
+        run_begin += run_len;
+    }
+    result
+}
+
 pub fn build_patch(other_data: &[u8], patch_commands: &PatchCommands) -> Patch {
     let mut patch_data: Vec<u8> = Vec::new();
     let mut other_cmds: Vec<CopyCmd> = Vec::new();
+    let mut fill_cmds: Vec<FillCmd> = Vec::new();
     for cmd in &patch_commands.other {
-        let patch_copy_cmd = CopyCmd {
-            source: patch_data.len() as u64,
-            target: cmd.target,
-            size: cmd.size,
-        };
         let slice_begin = cmd.source as usize;
         let slice_end = cmd.source as usize + cmd.size as usize;
         let slice = &other_data[slice_begin..slice_end];
-        patch_data.extend(slice.iter().cloned());
-        other_cmds.push(patch_copy_cmd);
+        let mut push_literal = |begin: usize, end: usize| {
+            if begin != end {
+                other_cmds.push(CopyCmd {
+                    source: patch_data.len() as u64,
+                    target: cmd.target + begin as u64,
+                    size: (end - begin) as u32,
+                });
+                patch_data.extend_from_slice(&slice[begin..end]);
+            }
+        };
+        let fill_runs = find_fill_runs(slice, cmd.target as usize, patch_commands.align);
+        let mut literal_begin: usize = 0;
+        for (run_begin, run_end) in fill_runs {
+            push_literal(literal_begin, run_begin);
+            fill_cmds.push(FillCmd {
+                target: cmd.target + run_begin as u64,
+                size: (run_end - run_begin) as u32,
+                byte: slice[run_begin],
+            });
+            literal_begin = run_end;
+        }
+        push_literal(literal_begin, slice.len());
     }
     let mut result = Patch {
         data: patch_data,
         base: patch_commands.base.clone(),
         other: other_cmds,
+        fill: fill_cmds,
         other_size: other_data.len() as u64,
     };
 
     optimize_copy_cmds(&mut result.base);
     optimize_copy_cmds(&mut result.other);
+    optimize_fill_cmds(&mut result.fill);
 
     result
 }
 
-pub fn apply_patch(base_data: &[u8], patch: &Patch) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    result.resize(patch.other_size as usize, 0);
-    for cmd in &patch.base {
-        cmd.execute(&mut result, &base_data);
+// Sort key that places similar literal chunks next to each other: chunks are grouped by
+// approximate entropy first and then by min-hash of their 4-byte substrings.
+fn literal_similarity_key(data: &[u8]) -> (u32, u32) {
+    let entropy = byte_entropy(data);
+    let min_hash = data
+        .windows(4)
+        .map(|window| u32::from_le_bytes([window[0], window[1], window[2], window[3]]))
+        .map(|x| x.wrapping_mul(0x9E37_79B1))
+        .min()
+        .unwrap_or(0);
+    ((entropy * 2.0) as u32, min_hash)
+}
+
+// Reorders literal data so that similar chunks are close to each other, which helps the
+// compressor to find matches between them. Patch application is not affected.
+pub fn reorder_literals(patch: &mut Patch) {
+    let mut keys: Vec<((u32, u32), usize)> = patch
+        .other
+        .par_iter()
+        .enumerate()
+        .map(|(i, cmd)| {
+            let begin = cmd.source as usize;
+            let end = begin + cmd.size as usize;
+            (literal_similarity_key(&patch.data[begin..end]), i)
+        })
+        .collect();
+    keys.sort_unstable();
+    let mut data: Vec<u8> = Vec::with_capacity(patch.data.len());
+    let mut other: Vec<CopyCmd> = Vec::with_capacity(patch.other.len());
+    for (_, i) in keys {
+        let cmd = &patch.other[i];
+        let begin = cmd.source as usize;
+        let end = begin + cmd.size as usize;
+        other.push(CopyCmd {
+            source: data.len() as u64,
+            target: cmd.target,
+            size: cmd.size,
+        });
+        data.extend_from_slice(&patch.data[begin..end]);
     }
-    for cmd in &patch.other {
-        cmd.execute(&mut result, &patch.data);
+    patch.data = data;
+    patch.other = other;
+}
+
+fn sorted_by_target<T>(cmds: &[T], target: fn(&T) -> u64) -> Vec<&T> {
+    let mut sorted: Vec<&T> = cmds.iter().collect();
+    sorted.sort_by_key(|cmd| target(cmd));
+    sorted
+}
+
+// Returns commands sorted by target that overlap output range [begin, end).
+// Targets of commands in one list never overlap, so their ends are sorted as well.
+fn region_commands<'a, T>(
+    cmds: &'a [&'a T],
+    begin: u64,
+    end: u64,
+    bounds: fn(&T) -> (u64, u64),
+) -> impl Iterator<Item = &'a T> {
+    let first = cmds.partition_point(|cmd| bounds(cmd).1 <= begin);
+    cmds[first..]
+        .iter()
+        .copied()
+        .take_while(move |cmd| bounds(cmd).0 < end)
+}
+
+// Patch commands sorted by target, so that any output region can be patched independently
+pub struct PatchRegions<'a> {
+    patch: &'a Patch,
+    base: Vec<&'a CopyCmd>,
+    other: Vec<&'a CopyCmd>,
+    fill: Vec<&'a FillCmd>,
+}
+
+impl<'a> PatchRegions<'a> {
+    pub fn new(patch: &'a Patch) -> Self {
+        Self {
+            patch,
+            base: sorted_by_target(&patch.base, |cmd| cmd.target),
+            other: sorted_by_target(&patch.other, |cmd| cmd.target),
+            fill: sorted_by_target(&patch.fill, |cmd| cmd.target),
+        }
     }
+
+    // Executes the parts of BASE, literal and fill commands that fall into the output region
+    // starting at `offset` in the same order as sequential application
+    pub fn apply(&self, base_data: &[u8], region: &mut [u8], offset: u64) {
+        let end = offset + region.len() as u64;
+        let copy_bounds = |cmd: &CopyCmd| (cmd.target, cmd.target + cmd.size as u64);
+        let fill_bounds = |cmd: &FillCmd| (cmd.target, cmd.target + cmd.size as u64);
+        for cmd in region_commands(&self.base, offset, end, copy_bounds) {
+            cmd.execute_region(region, offset, base_data);
+        }
+        for cmd in region_commands(&self.other, offset, end, copy_bounds) {
+            cmd.execute_region(region, offset, &self.patch.data);
+        }
+        for cmd in region_commands(&self.fill, offset, end, fill_bounds) {
+            cmd.execute_region(region, offset);
+        }
+    }
+}
+
+// Output is split into regions that are patched in parallel
+pub fn apply_patch(base_data: &[u8], patch: &Patch) -> Vec<u8> {
+    let mut result: Vec<u8> = vec![0; patch.other_size as usize];
+    let regions = PatchRegions::new(patch);
     result
+        .par_chunks_mut(APPLY_REGION_SIZE)
+        .enumerate()
+        .for_each(|(i, region)| {
+            regions.apply(base_data, region, (i * APPLY_REGION_SIZE) as u64);
+        });
+    result
+}
+
+// Part of a command that falls into output range [begin, end), moved to start at 0
+fn clip_target(target: u64, size: u32, begin: u64, end: u64) -> Option<(u64, u64, u32)> {
+    let clip_begin = max(target, begin);
+    let clip_end = min(target + size as u64, end);
+    (clip_begin < clip_end).then(|| {
+        let skipped = clip_begin - target;
+        (skipped, clip_begin - begin, (clip_end - clip_begin) as u32)
+    })
+}
+
+// Returns commands of a patch that produces only output range [begin, end) of the given patch.
+// Commands that don't intersect the range are dropped. Literal data is not copied, so literal
+// sources still refer to data of the given patch.
+pub fn clip_patch_commands(patch: &Patch, begin: u64, end: u64) -> Patch {
+    let clip_copy_cmds = |cmds: &[CopyCmd]| -> Vec<CopyCmd> {
+        cmds.iter()
+            .filter_map(|cmd| {
+                let (skipped, target, size) = clip_target(cmd.target, cmd.size, begin, end)?;
+                Some(CopyCmd {
+                    source: cmd.source + skipped,
+                    target,
+                    size,
+                })
+            })
+            .collect()
+    };
+    Patch {
+        data: Vec::new(),
+        base: clip_copy_cmds(&patch.base),
+        other: clip_copy_cmds(&patch.other),
+        fill: patch
+            .fill
+            .iter()
+            .filter_map(|cmd| {
+                let (_, target, size) = clip_target(cmd.target, cmd.size, begin, end)?;
+                Some(FillCmd {
+                    target,
+                    size,
+                    byte: cmd.byte,
+                })
+            })
+            .collect(),
+        other_size: end - begin,
+    }
+}
+
+// Reconstructs output range [begin, end) by executing only commands that intersect it
+pub fn apply_patch_range(base_data: &[u8], patch: &Patch, begin: u64, end: u64) -> Vec<u8> {
+    let mut clipped = clip_patch_commands(patch, begin, end);
+    for cmd in clipped.other.iter_mut() {
+        let source = cmd.source as usize;
+        cmd.source = clipped.data.len() as u64;
+        clipped
+            .data
+            .extend_from_slice(&patch.data[source..source + cmd.size as usize]);
+    }
+    apply_patch(base_data, &clipped)
 }
 
 #[cfg(test)]
@@ -0,0 +1,320 @@
+use crate::patchy::FillCmd;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+
+// Streams that decompress to more than this are ignored to avoid pathological memory use and
+// trial recompression time
+pub const MAX_EXPANDED_STREAM_SIZE: usize = 1 << 27;
+// Smaller streams are not worth the trial recompression cost
+pub const MIN_COMPRESSED_STREAM_SIZE: usize = 64;
+
+const ZSTD_FRAME_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const ZSTD_LEVELS: (i32, i32) = (1, 19);
+const ZLIB_LEVELS: (u32, u32) = (0, 9);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamCodec {
+    // zlib with default window and memory level
+    Zlib,
+    // Single zstd frame produced by one-shot compression (content size is stored in the header)
+    Zstd,
+    // Single zstd frame produced by streaming compression (content size is not stored)
+    ZstdStreaming,
+}
+
+// Compressed stream embedded in a file that can be reproduced bit-exactly from its decompressed
+// contents using the recorded codec and level.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompressedStream {
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub decompressed_size: u64,
+    pub codec: StreamCodec,
+    pub level: i32,
+}
+
+pub fn compress_stream(data: &[u8], codec: StreamCodec, level: i32) -> std::io::Result<Vec<u8>> {
+    match codec {
+        StreamCodec::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level as u32));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        StreamCodec::Zstd => {
+            let mut result: Vec<u8> = vec![0; zstd_safe::compress_bound(data.len())];
+            let size = zstd_safe::compress(&mut result[..], data, level)
+                .map_err(|code| Error::other(zstd_safe::get_error_name(code)))?;
+            result.truncate(size);
+            Ok(result)
+        }
+        StreamCodec::ZstdStreaming => zstd::stream::encode_all(data, level),
+    }
+}
+
+fn decompress_zlib(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut decoder = Decompress::new(true);
+    let mut output: Vec<u8> = Vec::with_capacity(1 << 16);
+    loop {
+        if output.len() == output.capacity() {
+            if output.len() >= MAX_EXPANDED_STREAM_SIZE {
+                return None;
+            }
+            output.reserve(output.capacity());
+        }
+        let input = &data[decoder.total_in() as usize..];
+        let status = decoder
+            .decompress_vec(input, &mut output, FlushDecompress::None)
+            .ok()?;
+        if status == Status::StreamEnd {
+            return Some((output, decoder.total_in() as usize));
+        }
+        if decoder.total_in() as usize == data.len() && output.len() < output.capacity() {
+            // Truncated stream
+            return None;
+        }
+    }
+}
+
+fn decompress_zstd(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let frame_size = zstd_safe::find_frame_compressed_size(data).ok()?;
+    if frame_size > data.len() {
+        return None;
+    }
+    let content_size = zstd_safe::get_frame_content_size(data);
+    if content_size != zstd_safe::CONTENTSIZE_UNKNOWN
+        && content_size > MAX_EXPANDED_STREAM_SIZE as u64
+    {
+        return None;
+    }
+    // Content size is optional, so the output is limited while decoding
+    let decoder = zstd::stream::Decoder::new(&data[..frame_size]).ok()?;
+    let mut output: Vec<u8> = Vec::new();
+    decoder
+        .take(MAX_EXPANDED_STREAM_SIZE as u64 + 1)
+        .read_to_end(&mut output)
+        .ok()?;
+    if output.len() > MAX_EXPANDED_STREAM_SIZE {
+        return None;
+    }
+    Some((output, frame_size))
+}
+
+fn is_zlib_header(data: &[u8]) -> bool {
+    // Deflate with 32KB window, which is what the encoder produces
+    data.len() >= 2
+        && data[0] == 0x78
+        && (((data[0] as u16) << 8) | data[1] as u16).is_multiple_of(31)
+}
+
+// Writer that only accepts output matching the expected stream, so that trial recompression
+// with wrong parameters stops at the first differing byte instead of compressing everything
+struct StreamMatcher<'a> {
+    expected: &'a [u8],
+}
+
+impl Write for StreamMatcher<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.expected.strip_prefix(buf) {
+            Some(rest) => {
+                self.expected = rest;
+                Ok(buf.len())
+            }
+            None => Err(Error::other("Recompressed stream differs")),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn is_reproducible(compressed: &[u8], decompressed: &[u8], codec: StreamCodec, level: i32) -> bool {
+    let matcher = StreamMatcher {
+        expected: compressed,
+    };
+    let result = match codec {
+        StreamCodec::Zlib => {
+            let mut encoder = ZlibEncoder::new(matcher, Compression::new(level as u32));
+            encoder
+                .write_all(decompressed)
+                .and_then(|_| encoder.finish())
+        }
+        StreamCodec::ZstdStreaming => {
+            zstd::stream::Encoder::new(matcher, level).and_then(|mut encoder| {
+                encoder.write_all(decompressed)?;
+                encoder.finish()
+            })
+        }
+        // One-shot compression output can't be compared while it is produced
+        StreamCodec::Zstd => {
+            return compress_stream(decompressed, codec, level)
+                .is_ok_and(|recompressed| recompressed == compressed)
+        }
+    };
+    matches!(result, Ok(matcher) if matcher.expected.is_empty())
+}
+
+fn find_reproducible_level(
+    compressed: &[u8],
+    decompressed: &[u8],
+    codec: StreamCodec,
+    levels: (i32, i32),
+) -> Option<i32> {
+    (levels.0..=levels.1).find(|&level| is_reproducible(compressed, decompressed, codec, level))
+}
+
+// Returns the stream at the start of data and its decompressed contents
+fn find_stream_at(data: &[u8]) -> Option<(CompressedStream, Vec<u8>)> {
+    let (decompressed, compressed_size, codec, levels) = if data.starts_with(&ZSTD_FRAME_MAGIC) {
+        let (decompressed, compressed_size) = decompress_zstd(data)?;
+        // Only one-shot compression stores content size in the frame header
+        let codec = match zstd_safe::get_frame_content_size(data) {
+            zstd_safe::CONTENTSIZE_UNKNOWN => StreamCodec::ZstdStreaming,
+            _ => StreamCodec::Zstd,
+        };
+        (decompressed, compressed_size, codec, ZSTD_LEVELS)
+    } else if is_zlib_header(data) {
+        let (decompressed, compressed_size) = decompress_zlib(data)?;
+        let levels = (ZLIB_LEVELS.0 as i32, ZLIB_LEVELS.1 as i32);
+        (decompressed, compressed_size, StreamCodec::Zlib, levels)
+    } else {
+        return None;
+    };
+    if compressed_size < MIN_COMPRESSED_STREAM_SIZE {
+        return None;
+    }
+    let compressed = &data[..compressed_size];
+    let level = find_reproducible_level(compressed, &decompressed, codec, levels)?;
+    let stream = CompressedStream {
+        offset: 0,
+        compressed_size: compressed_size as u64,
+        decompressed_size: decompressed.len() as u64,
+        codec,
+        level,
+    };
+    Some((stream, decompressed))
+}
+
+// Scans input for zlib and zstd streams that can be recompressed bit-exactly
+pub fn find_compressed_streams(data: &[u8]) -> Vec<CompressedStream> {
+    find_expanded_streams(data).0
+}
+
+// Same as find_compressed_streams, but also returns the input with streams replaced by their
+// decompressed contents, like expand_streams does, without decompressing them again
+pub fn find_expanded_streams(data: &[u8]) -> (Vec<CompressedStream>, Cow<'_, [u8]>) {
+    let mut result: Vec<CompressedStream> = Vec::new();
+    let mut expanded: Vec<u8> = Vec::new();
+    let mut pos: usize = 0;
+    let mut copied: usize = 0;
+    while pos + MIN_COMPRESSED_STREAM_SIZE <= data.len() {
+        match find_stream_at(&data[pos..]) {
+            Some((mut stream, decompressed)) => {
+                stream.offset = pos as u64;
+                expanded.extend_from_slice(&data[copied..pos]);
+                expanded.extend_from_slice(&decompressed);
+                pos += stream.compressed_size as usize;
+                copied = pos;
+                result.push(stream);
+            }
+            None => pos += 1,
+        }
+    }
+    if result.is_empty() {
+        return (result, Cow::Borrowed(data));
+    }
+    expanded.extend_from_slice(&data[copied..]);
+    (result, Cow::Owned(expanded))
+}
+
+pub fn expanded_size(data_size: u64, streams: &[CompressedStream]) -> u64 {
+    streams.iter().fold(data_size, |acc, stream| {
+        acc.saturating_sub(stream.compressed_size) + stream.decompressed_size
+    })
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Replaces compressed streams with their decompressed contents
+pub fn expand_streams<'a>(
+    data: &'a [u8],
+    streams: &[CompressedStream],
+) -> std::io::Result<Cow<'a, [u8]>> {
+    if streams.is_empty() {
+        return Ok(Cow::Borrowed(data));
+    }
+    let mut result: Vec<u8> = Vec::with_capacity(data.len());
+    let mut pos: usize = 0;
+    for stream in streams {
+        let stream_begin = stream.offset as usize;
+        let stream_end = stream_begin + stream.compressed_size as usize;
+        if stream_begin < pos || stream_end > data.len() {
+            return Err(invalid_data("Compressed stream is out of bounds"));
+        }
+        result.extend_from_slice(&data[pos..stream_begin]);
+        let stream_data = &data[stream_begin..stream_end];
+        let decompressed = match stream.codec {
+            StreamCodec::Zlib => decompress_zlib(stream_data).map(|(output, _)| output),
+            StreamCodec::Zstd | StreamCodec::ZstdStreaming => {
+                decompress_zstd(stream_data).map(|(output, _)| output)
+            }
+        }
+        .ok_or_else(|| invalid_data("Could not decompress embedded stream"))?;
+        if decompressed.len() as u64 != stream.decompressed_size {
+            return Err(invalid_data("Embedded stream size mismatch"));
+        }
+        result.extend_from_slice(&decompressed);
+        pos = stream_end;
+    }
+    result.extend_from_slice(&data[pos..]);
+    Ok(Cow::Owned(result))
+}
+
+// Inverse of expand_streams: recompresses stream contents using recorded parameters
+pub fn restore_streams(
+    expanded: Vec<u8>,
+    streams: &[CompressedStream],
+) -> std::io::Result<Vec<u8>> {
+    if streams.is_empty() {
+        return Ok(expanded);
+    }
+    let mut result: Vec<u8> = Vec::with_capacity(expanded.len());
+    let mut pos: usize = 0;
+    for stream in streams {
+        let gap_size = (stream.offset as usize)
+            .checked_sub(result.len())
+            .ok_or_else(|| invalid_data("Compressed streams overlap"))?;
+        let stream_begin = pos + gap_size;
+        let stream_end = stream_begin + stream.decompressed_size as usize;
+        if stream_end > expanded.len() {
+            return Err(invalid_data("Compressed stream is out of bounds"));
+        }
+        result.extend_from_slice(&expanded[pos..stream_begin]);
+        let compressed = compress_stream(
+            &expanded[stream_begin..stream_end],
+            stream.codec,
+            stream.level,
+        )?;
+        if compressed.len() as u64 != stream.compressed_size {
+            return Err(invalid_data("Embedded stream could not be reproduced"));
+        }
+        result.extend_from_slice(&compressed);
+        pos = stream_end;
+    }
+    result.extend_from_slice(&expanded[pos..]);
+    Ok(result)
+}
+
+// Fill commands target expanded data, so they only match the layout of restored data if there
+// are no embedded streams
+pub fn restored_fill_cmds<'a>(fill: &'a [FillCmd], streams: &[CompressedStream]) -> &'a [FillCmd] {
+    match streams.is_empty() {
+        true => fill,
+        false => &[],
+    }
+}
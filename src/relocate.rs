@@ -0,0 +1,244 @@
+use crate::hash::*;
+use crate::patchy::*;
+use crate::validate::*;
+use std::cmp::min;
+use std::collections::HashMap;
+
+// Number of BASE blocks that were found by relocation
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RelocationStats {
+    // Found at their original offsets
+    pub intact: usize,
+    // Found elsewhere in BASE
+    pub relocated: usize,
+}
+
+// Returns disjoint, sorted BASE ranges that copy commands read from
+fn source_ranges(patch: &Patch) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = patch
+        .base
+        .iter()
+        .filter(|cmd| cmd.size != 0)
+        .map(|cmd| (cmd.source, cmd.source + cmd.size as u64))
+        .collect();
+    ranges.sort_unstable();
+    let mut result: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (begin, end) in ranges {
+        match result.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+            _ => result.push((begin, end)),
+        }
+    }
+    result
+}
+
+// Splits BASE ranges that the patch copies from into sorted, disjoint blocks of `block_size`
+// bytes and hashes them. Ranges that are not a multiple of block size end with a shorter block.
+pub fn compute_source_blocks(base: &[u8], patch: &Patch, block_size: usize) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for (begin, end) in source_ranges(patch) {
+        let mut offset = begin;
+        while offset < end {
+            let block_end = min(offset + block_size as u64, end);
+            let data = &base[offset as usize..block_end as usize];
+            blocks.push(Block {
+                offset,
+                size: data.len() as u32,
+                hash_weak: compute_hash_weak(data),
+                hash_strong: compute_hash_strong(data),
+            });
+            offset = block_end;
+        }
+    }
+    blocks
+}
+
+// Where block data was found: bytes before `split` start at `first` and the rest at `second`.
+// Blocks that were found whole have `split` equal to their size.
+#[derive(Clone, Copy)]
+struct Placement {
+    split: u64,
+    first: u64,
+    second: u64,
+}
+
+impl Placement {
+    fn whole(block: &Block, offset: u64) -> Self {
+        Self {
+            split: block.size as u64,
+            first: offset,
+            second: offset + block.size as u64,
+        }
+    }
+    // Shifts of the first and the last byte of the block
+    fn deltas(&self, block: &Block) -> (i64, i64) {
+        let first = match self.split {
+            0 => self.second as i64 - block.offset as i64,
+            _ => self.first as i64 - block.offset as i64,
+        };
+        let last = match self.split == block.size as u64 {
+            true => self.first as i64 - block.offset as i64,
+            false => self.second as i64 - (block.offset + self.split) as i64,
+        };
+        (first, last)
+    }
+}
+
+fn block_matches(base: &[u8], block: &Block, offset: u64) -> bool {
+    let begin = offset as usize;
+    match base.get(begin..begin + block.size as usize) {
+        Some(data) => compute_hash_strong(data) == block.hash_strong,
+        None => false,
+    }
+}
+
+// Finds a block that is made of data at `first` followed by data at `second`, which is how a
+// block looks if data was inserted into or removed from it. Data at `second` is taken from the
+// same position in the block, so each split point is tried by updating the weak hash.
+fn find_split_block(base: &[u8], block: &Block, first: u64, second: u64) -> Option<Placement> {
+    let size = block.size as usize;
+    let slice = |offset: u64| base.get(offset as usize..(offset as usize).checked_add(size)?);
+    let (head, tail) = match (slice(first), slice(second)) {
+        (Some(head), Some(tail)) => (head, tail),
+        (Some(_), None) => {
+            return block_matches(base, block, first).then(|| Placement::whole(block, first))
+        }
+        (None, Some(_)) => {
+            return block_matches(base, block, second).then_some(Placement {
+                split: 0,
+                first,
+                second,
+            })
+        }
+        (None, None) => return None,
+    };
+    let mut rolling_hash = RollingHash::new();
+    rolling_hash.update(tail);
+    let (mut a, mut b) = (rolling_hash.get() as u16, (rolling_hash.get() >> 16) as u16);
+    for split in 0..=size {
+        if (a as u32 | (b as u32) << 16) == block.hash_weak {
+            let data: Vec<u8> = [&head[..split], &tail[split..]].concat();
+            if compute_hash_strong(&data) == block.hash_strong {
+                return Some(Placement {
+                    split: split as u64,
+                    first,
+                    second: second + split as u64,
+                });
+            }
+        }
+        if split < size {
+            // Bytes are offset the same way as in RollingHash
+            let delta = (head[split].wrapping_add(31) as u16)
+                .wrapping_sub(tail[split].wrapping_add(31) as u16);
+            a = a.wrapping_add(delta);
+            b = b.wrapping_add(((size - split) as u16).wrapping_mul(delta));
+        }
+    }
+    None
+}
+
+// Makes the patch copy from a modified BASE. Blocks that don't match at their original offsets
+// are searched for in the whole BASE using rolling hash. Blocks that are still missing, such as
+// the ones that data was inserted into and short blocks at the ends of ranges, are looked for at
+// the shifts of their neighbors. Copy commands are then moved to where their data was found.
+// Fails if any block is missing, since the patch needs all of them. Blocks must be validated
+// with the patch first.
+pub fn relocate_patch(
+    base: &[u8],
+    patch: &mut Patch,
+    blocks: &[Block],
+) -> Result<RelocationStats, PatchError> {
+    let mut stats = RelocationStats::default();
+    let mut placements: Vec<Option<Placement>> = blocks
+        .iter()
+        .map(|block| {
+            block_matches(base, block, block.offset).then(|| Placement::whole(block, block.offset))
+        })
+        .collect();
+    stats.intact = placements.iter().filter(|p| p.is_some()).count();
+
+    // Only full-size blocks are scanned for, shorter ones are placed below
+    let block_size = blocks.iter().map(|block| block.size).max().unwrap_or(0);
+    let missing: Vec<Block> = blocks
+        .iter()
+        .zip(placements.iter())
+        .filter(|(block, placement)| placement.is_none() && block.size == block_size)
+        .map(|(block, _)| block.clone())
+        .collect();
+    if !missing.is_empty() {
+        let found: HashMap<Hash128, u64> = find_blocks(base, &missing, block_size as usize);
+        for (block, placement) in blocks.iter().zip(placements.iter_mut()) {
+            if placement.is_none() && block.size == block_size {
+                *placement = found
+                    .get(&block.hash_strong)
+                    .map(|&offset| Placement::whole(block, offset));
+                stats.relocated += placement.is_some() as usize;
+            }
+        }
+    }
+
+    for index in 0..blocks.len() {
+        if placements[index].is_some() {
+            continue;
+        }
+        let block = &blocks[index];
+        let prev = (0..index)
+            .rev()
+            .find_map(|i| Some(placements[i]?.deltas(&blocks[i]).1));
+        let next =
+            (index + 1..blocks.len()).find_map(|i| Some(placements[i]?.deltas(&blocks[i]).0));
+        let (first, second) = match (prev.or(next), next.or(prev)) {
+            (Some(first), Some(second)) => (first, second),
+            _ => break,
+        };
+        let shift = |delta: i64| block.offset.checked_add_signed(delta);
+        placements[index] = match (shift(first), shift(second)) {
+            (Some(first), Some(second)) => find_split_block(base, block, first, second),
+            _ => None,
+        };
+        stats.relocated += placements[index].is_some() as usize;
+    }
+    if let Some(index) = placements.iter().position(|p| p.is_none()) {
+        return Err(PatchError::MissingBaseBlock {
+            offset: blocks[index].offset,
+        });
+    }
+
+    // Parts of blocks that are not covered by previous blocks, as (begin, end, new begin)
+    let mut segments: Vec<(u64, u64, u64)> = Vec::with_capacity(blocks.len());
+    for (block, placement) in blocks.iter().zip(placements.iter()) {
+        let placement = placement.unwrap();
+        let split = block.offset + placement.split;
+        let parts = [
+            (block.offset, split, placement.first),
+            (split, block.offset + block.size as u64, placement.second),
+        ];
+        for (part_begin, end, new_begin) in parts {
+            let begin = segments.last().map_or(part_begin, |s| s.1.max(part_begin));
+            if begin < end {
+                segments.push((begin, end, new_begin + (begin - part_begin)));
+            }
+        }
+    }
+    let mut base_cmds: Vec<CopyCmd> = Vec::with_capacity(patch.base.len());
+    for cmd in &patch.base {
+        let mut pos: u64 = 0;
+        while pos < cmd.size as u64 {
+            let source = cmd.source + pos;
+            let index = segments.partition_point(|s| s.1 <= source);
+            let (begin, end, new_begin) = match segments.get(index) {
+                Some(&segment) if segment.0 <= source => segment,
+                _ => return Err(PatchError::MissingBaseBlock { offset: source }),
+            };
+            let size = min(end - source, cmd.size as u64 - pos);
+            base_cmds.push(CopyCmd {
+                source: new_begin + (source - begin),
+                target: cmd.target + pos,
+                size: size as u32,
+            });
+            pos += size;
+        }
+    }
+    patch.base = base_cmds;
+    Ok(stats)
+}
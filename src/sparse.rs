@@ -0,0 +1,231 @@
+use crate::patchy::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::fs::File;
+
+// Largest fill command that is used for holes, a multiple of any supported alignment
+const HOLE_FILL_CHUNK_SIZE: u64 = 1 << 31;
+
+// Range of a sparse file that has no data allocated and reads as zeros
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hole {
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    // Returns None if there is no data at or after the offset
+    pub fn seek(file: &File, offset: u64, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+        match unsafe { libc::lseek64(file.as_raw_fd(), offset as libc::off64_t, whence) } {
+            -1 => {
+                let e = Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::ENXIO) => Ok(None),
+                    _ => Err(e),
+                }
+            }
+            pos => Ok(Some(pos as u64)),
+        }
+    }
+
+    pub fn punch_hole(file: &File, offset: u64, size: u64) -> std::io::Result<()> {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let result = unsafe {
+            libc::fallocate64(
+                file.as_raw_fd(),
+                mode,
+                offset as libc::off64_t,
+                size as libc::off64_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+}
+
+// Returns sorted holes of the file using SEEK_DATA and SEEK_HOLE. File systems that don't
+// support them report the whole file as data, so the result is empty.
+pub fn find_holes(file: &File) -> std::io::Result<Vec<Hole>> {
+    let mut holes: Vec<Hole> = Vec::new();
+    #[cfg(target_os = "linux")]
+    {
+        let size = file.metadata()?.len();
+        let mut pos: u64 = 0;
+        while pos < size {
+            let data_begin = linux::seek(file, pos, libc::SEEK_DATA)?.unwrap_or(size);
+            if data_begin > pos {
+                holes.push(Hole {
+                    offset: pos,
+                    size: data_begin - pos,
+                });
+            }
+            if data_begin >= size {
+                break;
+            }
+            pos = linux::seek(file, data_begin, libc::SEEK_HOLE)?.unwrap_or(size);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+    Ok(holes)
+}
+
+// Deallocates the range of the file, which then reads as zeros without changing file size
+pub fn punch_hole(file: &File, hole: &Hole) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return linux::punch_hole(file, hole.offset, hole.size);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, hole);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Punching holes is not supported on this platform",
+        ))
+    }
+}
+
+// Shrinks holes to multiples of `align` bytes, so that removing them keeps data aligned
+pub fn align_holes(holes: &[Hole], align: usize) -> Vec<Hole> {
+    let align = align as u64;
+    holes
+        .iter()
+        .filter_map(|hole| {
+            let begin = hole.offset.next_multiple_of(align);
+            let end = (hole.offset + hole.size) / align * align;
+            (begin < end).then_some(Hole {
+                offset: begin,
+                size: end - begin,
+            })
+        })
+        .collect()
+}
+
+// Data between holes as (offset in file, offset in compacted data, size)
+fn data_segments(size: u64, holes: &[Hole]) -> Vec<(u64, u64, u64)> {
+    let mut segments: Vec<(u64, u64, u64)> = Vec::with_capacity(holes.len() + 1);
+    let mut pos: u64 = 0;
+    let mut compact_pos: u64 = 0;
+    for hole in holes.iter().chain(std::iter::once(&Hole {
+        offset: size,
+        size: 0,
+    })) {
+        if hole.offset > pos {
+            segments.push((pos, compact_pos, hole.offset - pos));
+            compact_pos += hole.offset - pos;
+        }
+        pos = hole.offset + hole.size;
+    }
+    segments
+}
+
+// Returns file offset of a compacted data offset and the number of bytes until the end of its
+// data segment
+fn locate(segments: &[(u64, u64, u64)], compact_offset: u64) -> (u64, u64) {
+    let index = segments.partition_point(|s| s.1 + s.2 <= compact_offset);
+    let (offset, compact_begin, size) = segments[index];
+    let delta = compact_offset - compact_begin;
+    (offset + delta, size - delta)
+}
+
+fn compact_data(data: &[u8], segments: &[(u64, u64, u64)]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::with_capacity(segments.iter().map(|s| s.2 as usize).sum());
+    for &(offset, _, size) in segments {
+        result.extend_from_slice(&data[offset as usize..(offset + size) as usize]);
+    }
+    result
+}
+
+// Splits copy commands between compacted data at data segment boundaries and maps them to
+// file offsets
+fn expand_copy_cmds(
+    cmds: &[CopyCmd],
+    source_segments: &[(u64, u64, u64)],
+    target_segments: &[(u64, u64, u64)],
+) -> Vec<CopyCmd> {
+    let mut result: Vec<CopyCmd> = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let mut pos: u64 = 0;
+        while pos < cmd.size as u64 {
+            let (source, source_left) = locate(source_segments, cmd.source + pos);
+            let (target, target_left) = locate(target_segments, cmd.target + pos);
+            let size = min(min(source_left, target_left), cmd.size as u64 - pos);
+            result.push(CopyCmd {
+                source,
+                target,
+                size: size as u32,
+            });
+            pos += size;
+        }
+    }
+    result
+}
+
+// Same as compute_diff_trimmed_with, but holes of both inputs are removed before matching, so
+// that zeros they read as are neither hashed nor scanned. Holes must be aligned. Returned
+// commands don't cover OTHER holes, which are added to the patch by add_hole_fills. Block
+// layout refers to compacted OTHER data.
+pub fn compute_diff_sparse(
+    base: &[u8],
+    base_holes: &[Hole],
+    other: &[u8],
+    other_holes: &[Hole],
+    params: &DiffParams,
+) -> (PatchCommands, BlockLayout) {
+    let base_segments = data_segments(base.len() as u64, base_holes);
+    let other_segments = data_segments(other.len() as u64, other_holes);
+    let base_compact = compact_data(base, &base_segments);
+    let other_compact = compact_data(other, &other_segments);
+    let (mut compact_commands, layout) =
+        compute_diff_trimmed_with(&base_compact, &other_compact, params);
+    // Holes may still differ, so data is copied explicitly. Callers have to detect identical
+    // inputs themselves, since commands are never empty unless OTHER has no data.
+    if compact_commands.is_synchronized() {
+        compact_commands = PatchCommands::new_copy_base(other_compact.len(), params.align);
+    }
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.align = compact_commands.align;
+    patch_commands.prefix = compact_commands.prefix;
+    patch_commands.suffix = compact_commands.suffix;
+    patch_commands.recovered = compact_commands.recovered;
+    patch_commands.base = expand_copy_cmds(&compact_commands.base, &base_segments, &other_segments);
+    patch_commands.other =
+        expand_copy_cmds(&compact_commands.other, &other_segments, &other_segments);
+    (patch_commands, layout)
+}
+
+// Adds zero fill commands for OTHER holes, which apply leaves unallocated
+pub fn add_hole_fills(patch: &mut Patch, holes: &[Hole]) {
+    for hole in holes {
+        let end = hole.offset + hole.size;
+        let mut pos = hole.offset;
+        while pos < end {
+            let size = min(HOLE_FILL_CHUNK_SIZE, end - pos);
+            patch.fill.push(FillCmd {
+                target: pos,
+                size: size as u32,
+                byte: 0,
+            });
+            pos += size;
+        }
+    }
+    patch.fill.sort_by_key(|cmd| cmd.target);
+}
+
+// Punches holes into the output file, stopping if the file system doesn't support it, since
+// the holes are zero filled either way
+pub fn punch_holes(file: &File, holes: &[Hole]) -> std::io::Result<()> {
+    for hole in holes {
+        match punch_hole(file, hole) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+            result => result?,
+        }
+    }
+    Ok(())
+}
@@ -1,22 +1,78 @@
 use super::*;
 
 #[cfg(test)]
-fn do_test_patch(a: Vec<u8>, b: Vec<u8>, block_size: usize) {
-    let b_blocks = compute_blocks(&b, block_size);
-    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+fn make_test_header() -> PatchHeader {
+    PatchHeader {
+        base_hash: Hash128::new_zero(),
+        other_hash: Hash128::new_zero(),
+        decompress_inputs: false,
+        base_mask: Vec::new(),
+        other_masked: Vec::new(),
+        other_holes: Vec::new(),
+        other_regions: RegionHashes::default(),
+        base_blocks: Vec::new(),
+        filter: Filter::None,
+        base_streams: Vec::new(),
+        other_streams: Vec::new(),
+        block_layout: BlockLayout::Fixed { block_size: 64 },
+    }
+}
+
+// Temporary directory that is removed when the test ends, even if an assertion fails
+#[cfg(test)]
+struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("patchy_test_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+    fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+fn do_test_patch_commands(a: &[u8], b: &[u8], patch_commands: &PatchCommands) {
     let c = if patch_commands.is_synchronized() {
-        a
+        a.to_vec()
     } else {
-        let patch = build_patch(&b, &patch_commands);
-        apply_patch(&a, &patch)
+        let patch = build_patch(b, patch_commands);
+        let limits = PatchLimits::default();
+        assert_eq!(
+            validate_patch(&make_test_header(), &patch, a.len() as u64, &limits),
+            Ok(())
+        );
+        apply_patch(a, &patch)
     };
     if b.len() < 128 && c.len() < 128 {
-        assert_eq!(b, c);
+        assert_eq!(b, &c[..]);
     } else {
-        assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+        assert_eq!(compute_hash_strong(b), compute_hash_strong(&c));
     }
 }
 
+#[cfg(test)]
+fn do_test_patch(a: Vec<u8>, b: Vec<u8>, block_size: usize) {
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    let patch_commands = compute_diff_trimmed(&a, &b, block_size, 1);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    let patch_commands = compute_diff_reverse(&a, &b, block_size, 1);
+    do_test_patch_commands(&a, &b, &patch_commands);
+}
+
 #[test]
 fn test_patch_aaa_bbb() {
     do_test_patch(b"aaa".to_vec(), b"bbb".to_vec(), 2);
@@ -118,11 +174,1150 @@ fn test_optimize_commands_large() {
     }
     println!("original commands: {:?}", &cmds);
     assert_eq!(cmds.len(), 8);
-    let size_before = cmds.iter().map(|c| c.size as u64).fold(0, |acc, x| acc + x);
+    let size_before = cmds.iter().map(|c| c.size as u64).sum::<u64>();
     assert_eq!(size_before, 8u64 << 30);
     testing_optimize_copy_cmds(&mut cmds);
-    let size_after = cmds.iter().map(|c| c.size as u64).fold(0, |acc, x| acc + x);
+    let size_after = cmds.iter().map(|c| c.size as u64).sum::<u64>();
     assert_eq!(size_before, size_after);
     println!("optimized commands: {:?}", &cmds);
     assert_eq!(cmds.len(), 3);
 }
+
+#[test]
+fn test_patch_fill_runs() {
+    let mut a: Vec<u8> = Vec::new();
+    for i in 0..64 * 1024 {
+        a.push(i as u8);
+    }
+    let mut b: Vec<u8> = a.clone();
+    for x in b[1024..9024].iter_mut() {
+        *x = 0;
+    }
+    for x in b[20000..20100].iter_mut() {
+        *x = 0xCD;
+    }
+    let block_size = 64;
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.fill.len(), 2);
+    assert_eq!(patch.fill[0].target, 1024);
+    assert_eq!(patch.fill[0].size, 8000);
+    assert_eq!(patch.fill[0].byte, 0);
+    assert_eq!(patch.fill[1].byte, 0xCD);
+    assert!(patch.data.len() < 4 * block_size);
+    let c = apply_patch(&a, &patch);
+    assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+}
+
+#[cfg(test)]
+fn make_test_code(prefix_len: usize, call_count: usize) -> Vec<u8> {
+    // Simple pseudo-random instruction stream with calls to a fixed set of functions
+    let mut result: Vec<u8> = vec![0x90; prefix_len];
+    let mut seed: u32 = 12345;
+    for i in 0..call_count {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        result.resize(result.len() + (seed >> 28) as usize, 0x48);
+        let function_offset = ((i % 16) * 4096) as i64;
+        let next_offset = (result.len() + 5) as i64;
+        result.push(0xE8);
+        result.extend_from_slice(&((function_offset - next_offset) as i32).to_le_bytes());
+    }
+    result
+}
+
+#[test]
+fn test_filter_x86_roundtrip() {
+    let mut a = make_test_code(0, 10000);
+    a.extend_from_slice(&[0xE8, 0xFF, 0xFF, 0xFF]);
+    let mut b = Filter::X86.encode(&a).into_owned();
+    assert_ne!(a, b);
+    Filter::X86.decode(&mut b);
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_filter_x86_shifted_code() {
+    let a = make_test_code(0, 10000);
+    let b = make_test_code(7, 10000);
+    let block_size = 64;
+    let diff_size = |filter: Filter| -> usize {
+        let a_filtered = filter.encode(&a);
+        let b_filtered = filter.encode(&b);
+        let b_blocks = compute_blocks(&b_filtered, block_size);
+        let patch_commands = compute_diff(&a_filtered, &b_blocks, block_size);
+        let patch = build_patch(&b_filtered, &patch_commands);
+        let mut c = apply_patch(&a_filtered, &patch);
+        filter.decode(&mut c);
+        assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+        patch.data.len()
+    };
+    assert!(diff_size(Filter::X86) * 10 < diff_size(Filter::None));
+}
+
+#[cfg(test)]
+fn make_test_text(seed: u32, len: usize) -> Vec<u8> {
+    let words: [&[u8]; 8] = [
+        b"patch ", b"block ", b"hash ", b"base ", b"other ", b"diff ", b"data ", b"file ",
+    ];
+    let mut result: Vec<u8> = Vec::new();
+    let mut seed = seed;
+    while result.len() < len {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        result.extend_from_slice(words[(seed >> 24) as usize % words.len()]);
+    }
+    result.truncate(len);
+    result
+}
+
+#[cfg(test)]
+fn make_test_container(texts: &[Vec<u8>]) -> Vec<u8> {
+    let mut result: Vec<u8> = b"header".to_vec();
+    result.extend(compress_stream(&texts[0], StreamCodec::Zlib, 6).unwrap());
+    result.extend_from_slice(b"separator");
+    result.extend(compress_stream(&texts[1], StreamCodec::ZstdStreaming, 3).unwrap());
+    result.extend(compress_stream(&texts[2], StreamCodec::Zstd, 7).unwrap());
+    result.extend_from_slice(b"footer");
+    result
+}
+
+#[test]
+fn test_precomp_roundtrip() {
+    let texts: Vec<Vec<u8>> = (0..3).map(|i| make_test_text(i, 100000)).collect();
+    let a = make_test_container(&texts);
+    let streams = find_compressed_streams(&a);
+    assert_eq!(streams.len(), 3);
+    assert_eq!(streams[0].codec, StreamCodec::Zlib);
+    assert_eq!(streams[0].level, 6);
+    assert_eq!(streams[1].codec, StreamCodec::ZstdStreaming);
+    assert_eq!(streams[2].codec, StreamCodec::Zstd);
+    assert_eq!(streams[2].level, 7);
+    let expanded = expand_streams(&a, &streams).unwrap();
+    assert_eq!(
+        expanded.len() as u64,
+        expanded_size(a.len() as u64, &streams)
+    );
+    let (found_streams, found_expanded) = find_expanded_streams(&a);
+    assert_eq!(found_streams.len(), streams.len());
+    assert!(found_expanded == expanded);
+    let restored = restore_streams(expanded.into_owned(), &streams).unwrap();
+    assert_eq!(a, restored);
+    // Streams that expand to more than the limit are not recompressed
+    let zeros: Vec<u8> = vec![0; MAX_EXPANDED_STREAM_SIZE + 1];
+    let large = compress_stream(&zeros, StreamCodec::ZstdStreaming, 1).unwrap();
+    assert!(find_compressed_streams(&large).is_empty());
+}
+
+#[test]
+fn test_precomp_patch() {
+    let mut texts: Vec<Vec<u8>> = (0..3).map(|i| make_test_text(i, 100000)).collect();
+    let a = make_test_container(&texts);
+    for text in texts.iter_mut() {
+        text[50000] = b'!';
+    }
+    let b = make_test_container(&texts);
+    let a_streams = find_compressed_streams(&a);
+    let b_streams = find_compressed_streams(&b);
+    let a_expanded = expand_streams(&a, &a_streams).unwrap();
+    let b_expanded = expand_streams(&b, &b_streams).unwrap();
+    let block_size = 64;
+    let b_blocks = compute_blocks(&b_expanded, block_size);
+    let patch_commands = compute_diff(&a_expanded, &b_blocks, block_size);
+    let patch = build_patch(&b_expanded, &patch_commands);
+    assert_eq!(b_streams.len(), 3);
+    assert!(patch.data.len() < 16 * block_size);
+    let c = restore_streams(apply_patch(&a_expanded, &patch), &b_streams).unwrap();
+    assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+}
+
+#[test]
+fn test_precomp_fill() {
+    let mut texts: Vec<Vec<u8>> = (0..3).map(|i| make_test_text(i, 100000)).collect();
+    let a = make_test_container(&texts);
+    texts[2][80000..96000].fill(0);
+    let b = make_test_container(&texts);
+    let (_, a_expanded) = find_expanded_streams(&a);
+    let (b_streams, b_expanded) = find_expanded_streams(&b);
+    let block_size = 64;
+    let b_blocks = compute_blocks(&b_expanded, block_size);
+    let patch_commands = compute_diff(&a_expanded, &b_blocks, block_size);
+    let patch = build_patch(&b_expanded, &patch_commands);
+    // Fill targets are offsets in expanded data, which are past the end of restored data
+    assert_eq!(patch.fill.len(), 1);
+    assert!(patch.fill[0].target > b.len() as u64);
+    assert!(restored_fill_cmds(&patch.fill, &b_streams).is_empty());
+    assert_eq!(restored_fill_cmds(&patch.fill, &[]), &patch.fill[..]);
+    let c = restore_streams(apply_patch(&a_expanded, &patch), &b_streams).unwrap();
+    assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+}
+
+#[test]
+fn test_file_compression_roundtrip() {
+    let a = make_test_text(3, 100000);
+    assert_eq!(FileCompression::detect(&a), FileCompression::None);
+    for &compression in &[FileCompression::Zstd, FileCompression::Gzip] {
+        let compressed = compression.compress(&a).unwrap();
+        assert_eq!(FileCompression::detect(&compressed), compression);
+        assert_eq!(compression.decompress(&compressed).unwrap(), a);
+        let limit = a.len() as u64 - 1;
+        assert!(compression.decompress_limited(&compressed, limit).is_err());
+        assert_eq!(
+            compression
+                .decompress_limited(&compressed, u64::MAX)
+                .unwrap(),
+            a
+        );
+    }
+    // Raw data that starts with gzip magic
+    let mut b: Vec<u8> = vec![0x1F, 0x8B];
+    b.extend_from_slice(&a);
+    assert_eq!(FileCompression::detect(&b), FileCompression::None);
+    b.splice(2..2, [0x08, 0x00]);
+    assert_eq!(FileCompression::detect(&b), FileCompression::Gzip);
+    assert!(FileCompression::Gzip.decompress(&b).is_err());
+}
+
+#[test]
+fn test_patch_aligned() {
+    let a = make_test_text(4, 256 * 1024);
+    let mut b: Vec<u8> = vec![0xAA; 128];
+    b.extend_from_slice(&a[..128 * 1024]);
+    b.extend_from_slice(&[0; 10000]);
+    b.extend_from_slice(&a[128 * 1024..]);
+    let block_size = 256;
+    let align = 64;
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff_aligned(&a, &b_blocks, block_size, align);
+    let patch = build_patch(&b, &patch_commands);
+    for cmd in &patch.base {
+        assert_eq!(cmd.source as usize % align, 0);
+        assert_eq!(cmd.target as usize % align, 0);
+    }
+    for cmd in &patch.other {
+        assert_eq!(cmd.target as usize % align, 0);
+    }
+    for cmd in &patch.fill {
+        assert_eq!(cmd.target as usize % align, 0);
+        assert_eq!(cmd.size as usize % align, 0);
+    }
+    assert!(!patch.base.is_empty());
+    assert!(!patch.fill.is_empty());
+    let c = apply_patch(&a, &patch);
+    assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+    // Alignment that doesn't divide block size is reduced
+    let patch_commands = compute_diff_aligned(&a, &b_blocks, block_size, 96);
+    assert_eq!(patch_commands.align, 32);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    let patch_commands = compute_diff_reverse(&a, &b, block_size, 0);
+    assert_eq!(patch_commands.align, 1);
+    do_test_patch_commands(&a, &b, &patch_commands);
+}
+
+#[test]
+fn test_common_prefix_suffix() {
+    let a = make_test_text(5, 300000);
+    let mut b = a.clone();
+    b.extend_from_slice(b"appended");
+    assert_eq!(find_common_prefix_suffix(&a, &b, 1), (a.len(), 0));
+    let mut b = a.clone();
+    b[100000] = b'!';
+    assert_eq!(
+        find_common_prefix_suffix(&a, &b, 1),
+        (100000, a.len() - 100001)
+    );
+    assert_eq!(find_common_prefix_suffix(&a, &b, 64), (99968, 199968));
+    let mut c = b"XYZ".to_vec();
+    c.extend_from_slice(&a);
+    assert_eq!(find_common_prefix_suffix(&a, &c, 1), (0, a.len()));
+    assert_eq!(find_common_prefix_suffix(&a, &a, 64), (299968, 32));
+}
+
+#[test]
+fn test_patch_trimmed_appended() {
+    let a = make_test_text(6, 1 << 20);
+    let mut b = a.clone();
+    b.extend(make_test_text(7, 1000));
+    b[10] = b'!';
+    let block_size = 64;
+    let patch_commands = compute_diff_trimmed(&a, &b, block_size, 1);
+    assert_eq!(patch_commands.prefix, 10);
+    assert!(patch_commands.need_bytes_from_other() <= 1000 + 2 * block_size);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    assert!(compute_diff_trimmed(&a, &a, block_size, 64).is_synchronized());
+    do_test_patch_commands(&a, &b, &compute_diff_trimmed(&a, &b, block_size, 64));
+}
+
+#[test]
+fn test_mask_spec_parse() {
+    assert_eq!(
+        MaskSpec::parse("0x40:16"),
+        Some(MaskSpec::Range {
+            offset: 64,
+            size: 16
+        })
+    );
+    assert_eq!(
+        MaskSpec::parse("pattern:ABcd01:8"),
+        Some(MaskSpec::Pattern {
+            pattern: vec![0xAB, 0xCD, 0x01],
+            size: 8
+        })
+    );
+    assert_eq!(MaskSpec::parse("pattern:abc:8"), None);
+    assert_eq!(MaskSpec::parse("12"), None);
+    assert_eq!(MaskSpec::parse("x:12"), None);
+}
+
+#[test]
+fn test_patch_masked() {
+    let mut a = make_test_text(8, 64 * 1024);
+    let mut b = a.clone();
+    a[1000..1008].copy_from_slice(b"TIME=111");
+    b[1000..1008].copy_from_slice(b"TIME=222");
+    a[40000..40004].copy_from_slice(b"\x01\x02\x03\x04");
+    b[40000..40004].copy_from_slice(b"\x05\x06\x07\x08");
+    let specs = [
+        MaskSpec::parse("pattern:54494d453d:3").unwrap(),
+        MaskSpec::parse("40000:4").unwrap(),
+    ];
+    let a_mask = find_mask_ranges(&a, &specs);
+    let b_mask = find_mask_ranges(&b, &specs);
+    assert_eq!(b_mask.len(), 2);
+    let b_masked_regions = extract_masked_regions(&b, &b_mask);
+    let a_masked = mask_data(&a, &a_mask);
+    let b_masked = mask_data(&b, &b_mask);
+    let block_size = 64;
+    let patch_commands = compute_diff_trimmed(&a_masked, &b_masked, block_size, 1);
+    assert!(patch_commands.is_synchronized());
+    let mut c = a_masked.into_owned();
+    restore_masked_regions(&mut c, &b_masked_regions);
+    assert_eq!(b, c);
+}
+
+#[test]
+fn test_patch_reorder_literals() {
+    let a = make_test_text(9, 256 * 1024);
+    let mut b = a.clone();
+    // Text and binary noise have different similarity keys
+    let text_noise = make_test_text(100, 64);
+    let binary_noise: Vec<u8> = (0..64).map(|j| (j * 97) as u8).collect();
+    for i in 0..64 {
+        let pos = i * 4096 + 1024;
+        let noise = if i % 2 == 0 {
+            &text_noise
+        } else {
+            &binary_noise
+        };
+        b[pos..pos + 64].copy_from_slice(noise);
+    }
+    let block_size = 64;
+    let patch_commands = compute_diff_trimmed(&a, &b, block_size, 1);
+    let mut patch = build_patch(&b, &patch_commands);
+    let data_len = patch.data.len();
+    // Noise variants alternate in OTHER order and are contiguous after reordering
+    let variant_changes = |data: &[u8]| {
+        let chunks: Vec<&[u8]> = data.chunks(64).collect();
+        chunks.windows(2).filter(|w| w[0] != w[1]).count()
+    };
+    assert_eq!(data_len, 64 * 64);
+    assert_eq!(variant_changes(&patch.data), 63);
+    reorder_literals(&mut patch);
+    assert_eq!(patch.data.len(), data_len);
+    assert_eq!(variant_changes(&patch.data), 1);
+    let c = apply_patch(&a, &patch);
+    assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+}
+
+#[test]
+fn test_patch_serialize_roundtrip() {
+    let a = make_test_text(10, 128 * 1024);
+    let mut b = a.clone();
+    b[5000..5100].copy_from_slice(&make_test_text(11, 100));
+    b[70000..72048].fill(0);
+    let block_size = 64;
+    let patch_commands = compute_diff_trimmed(&a, &b, block_size, 1);
+    let patch = build_patch(&b, &patch_commands);
+    let header = PatchHeader {
+        base_hash: compute_hash_strong(&a),
+        other_hash: compute_hash_strong(&b),
+        decompress_inputs: false,
+        base_mask: Vec::new(),
+        other_masked: Vec::new(),
+        other_holes: Vec::new(),
+        other_regions: RegionHashes::default(),
+        base_blocks: Vec::new(),
+        filter: Filter::None,
+        base_streams: Vec::new(),
+        other_streams: Vec::new(),
+        block_layout: BlockLayout::Fixed { block_size: 64 },
+    };
+    let codecs = PatchCodecs {
+        header: SectionCodec::new(Codec::None, 0),
+        commands: SectionCodec::new(Codec::Zstd, 19),
+        data: SectionCodec {
+            codec: Codec::Zstd,
+            level: 3,
+            long_distance: true,
+        },
+        data_chunk_size: None,
+    };
+    let (serialized, stats) = serialize_patch(header, &patch, &codecs, None).unwrap();
+    assert_eq!(stats.len(), 4);
+    let (header, decoded) = deserialize_patch(&serialized, None).unwrap();
+    assert_eq!(header.other_hash, compute_hash_strong(&b));
+    assert_eq!(decoded.base, patch.base);
+    assert_eq!(decoded.other, patch.other);
+    assert_eq!(decoded.fill, patch.fill);
+    assert_eq!(decoded.data, patch.data);
+    let c = apply_patch(&a, &decoded);
+    assert_eq!(compute_hash_strong(&b), compute_hash_strong(&c));
+    assert!(deserialize_patch(&serialized[..serialized.len() / 2], None).is_err());
+
+    // Command size that doesn't fit in 32 bits is rejected instead of being truncated
+    let mut patch = build_patch(&b, &patch_commands);
+    patch.fill = vec![FillCmd {
+        target: 0,
+        size: u32::MAX,
+        byte: 0,
+    }];
+    let raw_codecs = PatchCodecs {
+        header: SectionCodec::new(Codec::None, 0),
+        commands: SectionCodec::new(Codec::None, 0),
+        data: SectionCodec::new(Codec::None, 0),
+        data_chunk_size: None,
+    };
+    let (mut serialized, _) =
+        serialize_patch(make_test_header(), &patch, &raw_codecs, None).unwrap();
+    let size_varint = [0xFF, 0xFF, 0xFF, 0xFF, 0x0F];
+    let pos = serialized
+        .windows(size_varint.len())
+        .position(|window| window == size_varint)
+        .unwrap();
+    assert!(deserialize_patch(&serialized, None).is_ok());
+    serialized[pos + 4] = 0x1F;
+    assert!(deserialize_patch(&serialized, None).is_err());
+}
+
+#[test]
+fn test_section_codecs() {
+    let data = make_test_text(12, 100 * 1024);
+    for name in Codec::NAMES.iter() {
+        let codec = Codec::from_name(name).unwrap();
+        assert_eq!(codec.name(), *name);
+        let section_codec = SectionCodec::new(codec, codec.default_level());
+        let compressed = section_codec.compress(&data, None).unwrap();
+        if codec != Codec::None {
+            assert!(compressed.len() < data.len());
+        }
+        assert_eq!(section_codec.decompress(&compressed, None).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_patch_dictionary() {
+    let samples: Vec<Vec<u8>> = (0..64).map(|i| make_test_text(13 + i, 4096)).collect();
+    let dictionary = Dictionary::train(&samples, 16 * 1024).unwrap();
+    let a = make_test_text(100, 8192);
+    let mut b = a.clone();
+    b[1000..1100].copy_from_slice(&make_test_text(101, 100));
+    let patch_commands = compute_diff_trimmed(&a, &b, 64, 1);
+    let patch = build_patch(&b, &patch_commands);
+    let header = PatchHeader {
+        base_hash: compute_hash_strong(&a),
+        other_hash: compute_hash_strong(&b),
+        decompress_inputs: false,
+        base_mask: Vec::new(),
+        other_masked: Vec::new(),
+        other_holes: Vec::new(),
+        other_regions: RegionHashes::default(),
+        base_blocks: Vec::new(),
+        filter: Filter::None,
+        base_streams: Vec::new(),
+        other_streams: Vec::new(),
+        block_layout: BlockLayout::Fixed { block_size: 64 },
+    };
+    let codecs = PatchCodecs {
+        header: SectionCodec::new(Codec::Zstd, 19),
+        commands: SectionCodec::new(Codec::Zstd, 19),
+        data: SectionCodec {
+            codec: Codec::Zstd,
+            level: 19,
+            long_distance: true,
+        },
+        data_chunk_size: None,
+    };
+    let (serialized, _) = serialize_patch(header, &patch, &codecs, Some(&dictionary)).unwrap();
+    assert_eq!(
+        patch_dictionary_id(&serialized).unwrap(),
+        Some(dictionary.id)
+    );
+    assert!(deserialize_patch(&serialized, None).is_err());
+    let (_, decoded) = deserialize_patch(&serialized, Some(&dictionary)).unwrap();
+    let c = apply_patch(&a, &decoded);
+    assert_eq!(b, c);
+}
+
+#[test]
+fn test_patch_adaptive_blocks() {
+    // Structured text followed by high entropy data
+    let mut a = make_test_text(14, 256 * 1024);
+    let mut state: u32 = 1;
+    a.extend((0..256 * 1024).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    }));
+    let mut b = a.clone();
+    for &pos in [0, 1000, 100_000, 300_000, a.len() - 1].iter() {
+        b[pos] ^= 1;
+    }
+    let mut params = DiffParams::new(64, 1);
+    params.block_size_bounds = (64, 4096);
+    params.direction = DiffDirection::Forward;
+    let (patch_commands, layout) = compute_diff_trimmed_with(&a, &b, &params);
+    assert_eq!(layout.block_sizes(), vec![64, 4096]);
+    assert_eq!(layout.block_size_at(0), 64);
+    assert_eq!(layout.block_size_at(300_000), 4096);
+    assert_eq!(patch_commands.need_bytes_from_other(), 64 * 3 + 4096 * 2);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    // Bounds are rounded up to powers of two
+    let layout = BlockLayout::new(&a, (48, 3000));
+    assert_eq!(layout.block_sizes(), vec![64, 4096]);
+}
+
+#[test]
+fn test_patch_overlapping_signatures() {
+    // Every other block of OTHER starts with a new byte, the rest is shifted BASE data
+    let a = make_test_text(15, 64 * 1024);
+    let block_size = 1024;
+    let mut b: Vec<u8> = Vec::new();
+    for (i, chunk) in a.chunks(block_size).enumerate() {
+        if i % 2 == 1 {
+            b.push(0xFF);
+        }
+        b.extend_from_slice(chunk);
+    }
+    let mut params = DiffParams::new(block_size, 1);
+    params.direction = DiffDirection::Forward;
+    let (sparse, _) = compute_diff_trimmed_with(&a, &b, &params);
+    params.density = 4;
+    let (dense, _) = compute_diff_trimmed_with(&a, &b, &params);
+    assert_eq!(sparse.recovered, 0);
+    assert!(dense.recovered > 0);
+    assert_eq!(
+        dense.need_bytes_from_other() + dense.recovered,
+        sparse.need_bytes_from_other()
+    );
+    do_test_patch_commands(&a, &b, &sparse);
+    do_test_patch_commands(&a, &b, &dense);
+}
+
+#[test]
+fn test_patch_reverse() {
+    let a = make_test_text(16, 1024 * 1024);
+    let mut b = a[300_000..340_000].to_vec();
+    b[5000..5005].copy_from_slice(b"patch");
+    b.extend_from_slice(&a[700_003..720_000]);
+    let block_size = 256;
+    let mut params = DiffParams::new(block_size, 1);
+    assert_eq!(
+        params.direction.resolve(a.len(), b.len()),
+        DiffDirection::Reverse
+    );
+    params.direction = DiffDirection::Reverse;
+    let (patch_commands, _) = compute_diff_trimmed_with(&a, &b, &params);
+    // Matches are extended to exact boundaries of the changed bytes
+    assert_eq!(patch_commands.need_bytes_from_other(), 5);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    let patch_commands = compute_diff_reverse(&a, &a, block_size, 1);
+    assert!(patch_commands.is_synchronized());
+    let patch_commands = compute_diff_reverse(&a, &b, block_size, 64);
+    assert_eq!(patch_commands.align, 64);
+    do_test_patch_commands(&a, &b, &patch_commands);
+}
+
+#[test]
+fn test_patch_with_signature() {
+    let b = make_test_text(17, 200 * 1024);
+    let mut params = DiffParams::new(256, 1);
+    params.direction = DiffDirection::Forward;
+    params.density = 2;
+    let signature = Signature::new(&b, &params);
+    let mut inputs: Vec<Vec<u8>> = Vec::new();
+    inputs.push(b.clone());
+    inputs.push(b[..150 * 1024].to_vec());
+    let mut a = b.clone();
+    a[1000..1300].copy_from_slice(&make_test_text(18, 300));
+    a.insert(100_000, 0xAA);
+    inputs.push(a);
+    inputs.push(make_test_text(19, 1000));
+    for a in &inputs {
+        let patch_commands = compute_diff_with_signature(a, &b, &signature, 1);
+        do_test_patch_commands(a, &b, &patch_commands);
+    }
+    let patch_commands = compute_diff_with_signature(&inputs[1], &b, &signature, 1);
+    assert_eq!(patch_commands.prefix, 150 * 1024);
+    assert_eq!(patch_commands.need_bytes_from_other(), 50 * 1024);
+}
+
+#[test]
+fn test_signature_cache() {
+    let test_dir = TestDir::new("cache");
+    let dir = test_dir.path();
+    let cache = SignatureCache::new(dir);
+    let b = make_test_text(20, 100 * 1024);
+    let hash = compute_hash_strong(&b);
+    let params = DiffParams::new(256, 1);
+    assert!(cache.load(hash, b.len(), &params).is_none());
+    let signature = Signature::new(&b, &params);
+    cache.store(hash, b.len(), &params, &signature).unwrap();
+    let cached = cache.load(hash, b.len(), &params).unwrap();
+    assert_eq!(cached.layout, signature.layout);
+    assert_eq!(cached.blocks.len(), signature.blocks.len());
+    let mut a = b.clone();
+    a[5000..5100].copy_from_slice(&make_test_text(21, 100));
+    let patch_commands = compute_diff_with_signature(&a, &b, &cached, 1);
+    do_test_patch_commands(&a, &b, &patch_commands);
+    // Different parameters or contents use different entries
+    assert!(cache
+        .load(hash, b.len(), &DiffParams::new(512, 1))
+        .is_none());
+    assert!(cache
+        .load(compute_hash_strong(&a), a.len(), &params)
+        .is_none());
+}
+
+#[test]
+fn test_apply_patch_to_file() {
+    let test_dir = TestDir::new("apply");
+    let dir = test_dir.path();
+    let mut a = make_test_text(22, 300 * 1024);
+    a[100_000..110_000].fill(0);
+    let mut b = a.clone();
+    b[5000..5100].copy_from_slice(&make_test_text(23, 100));
+    b[200_000..204_000].fill(0);
+    b[250_000..254_000].fill(0xCC);
+    b.splice(8192..8192, make_test_text(24, 4096));
+    for align in [1, 4096] {
+        let patch_commands = compute_diff_trimmed(&a, &b, 4096, align);
+        let patch = build_patch(&b, &patch_commands);
+        let base_path = dir.join("base");
+        let output_path = dir.join(format!("output{}", align));
+        std::fs::write(&base_path, &a).unwrap();
+        let base_file = std::fs::File::open(&base_path).unwrap();
+        let output_file = std::fs::File::create(&output_path).unwrap();
+        let stats = apply_patch_to_file(&base_file, &a, &patch, &output_file).unwrap();
+        drop(output_file);
+        assert_eq!(std::fs::read(&output_path).unwrap(), b);
+        let fill_size: u64 = patch
+            .fill
+            .iter()
+            .filter(|cmd| cmd.byte != 0)
+            .map(|cmd| cmd.size as u64)
+            .sum();
+        assert_eq!(
+            stats.cloned + stats.copied + stats.written,
+            patch_commands.need_bytes_from_base() as u64 + patch.data.len() as u64 + fill_size
+        );
+    }
+}
+
+#[test]
+fn test_resume_apply_patch() {
+    let test_dir = TestDir::new("resume");
+    let dir = test_dir.path();
+    let a = make_test_text(25, 400 * 1024);
+    let mut b = a.clone();
+    for i in 0..20 {
+        let offset = i * 20_000;
+        b[offset..offset + 100].copy_from_slice(&make_test_text(26 + i as u32, 100));
+    }
+    let patch_commands = compute_diff_trimmed(&a, &b, 1024, 1);
+    let patch = build_patch(&b, &patch_commands);
+    let command_count = patch_command_count(&patch);
+    assert!(command_count > 10);
+
+    let base_path = dir.join("base");
+    let output_path = dir.join("output");
+    std::fs::write(&base_path, &a).unwrap();
+    let base_file = std::fs::File::open(&base_path).unwrap();
+    let journal_path = ApplyJournal::path_for(&output_path);
+    let journal_header = JournalHeader {
+        patch_hash: compute_hash_strong(&patch.data),
+        other_hash: compute_hash_strong(&b),
+        other_size: b.len() as u64,
+    };
+
+    // Interrupted application: only some commands are completed and the last record is torn
+    let output_file = std::fs::File::create(&output_path).unwrap();
+    output_file.set_len(patch.other_size).unwrap();
+    let partial = Patch {
+        data: Vec::new(),
+        base: patch.base[..patch.base.len() / 2].to_vec(),
+        other: Vec::new(),
+        fill: Vec::new(),
+        other_size: patch.other_size,
+    };
+    apply_patch_to_file_from(&base_file, &a, &partial, &output_file, 0, None).unwrap();
+    let mut journal = ApplyJournal::create(&journal_path, journal_header, &output_path).unwrap();
+    journal.record(1).unwrap();
+    journal.record(partial.base.len() as u64).unwrap();
+    drop(journal);
+    let mut journal_file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&journal_path)
+        .unwrap();
+    std::io::Write::write_all(&mut journal_file, &[0xFF; 3]).unwrap();
+    drop(journal_file);
+
+    let mut other_header = journal_header;
+    other_header.other_size += 1;
+    assert!(ApplyJournal::open(&journal_path, other_header).is_err());
+    let (mut journal, completed) = ApplyJournal::open(&journal_path, journal_header).unwrap();
+    assert_eq!(completed, partial.base.len() as u64);
+    assert_eq!(journal.temp_path(), output_path);
+    let output_file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&output_path)
+        .unwrap();
+    apply_patch_to_file_from(
+        &base_file,
+        &a,
+        &patch,
+        &output_file,
+        completed,
+        Some(&mut |completed| journal.record(completed)),
+    )
+    .unwrap();
+    drop(output_file);
+    assert_eq!(std::fs::read(&output_path).unwrap(), b);
+    journal.remove().unwrap();
+    assert!(!journal_path.exists());
+}
+
+#[test]
+fn test_commit_output() {
+    let test_dir = TestDir::new("output");
+    let dir = test_dir.path();
+    let base_path = dir.join("base");
+    let output_path = dir.join("output");
+    std::fs::write(&base_path, b"base").unwrap();
+    std::fs::write(&output_path, b"old output").unwrap();
+    assert!(is_same_file(&base_path, &base_path).unwrap());
+    assert!(!is_same_file(&base_path, &output_path).unwrap());
+    assert!(!is_same_file(&base_path, &dir.join("missing")).unwrap());
+
+    // Existing files and other temporary files are never reused
+    let user_path = dir.join("output.tmp");
+    std::fs::write(&user_path, b"user data").unwrap();
+    let (other_file, other_temp_path) = create_output_temp(&output_path).unwrap();
+    let (mut output_file, temp_path) = create_output_temp(&output_path).unwrap();
+    assert_ne!(temp_path, other_temp_path);
+    drop(other_file);
+    std::io::Write::write_all(&mut output_file, b"new output").unwrap();
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    let options = MetadataOptions {
+        mtime: Some(mtime),
+        ..Default::default()
+    };
+    let base_file = std::fs::File::open(&base_path).unwrap();
+    apply_metadata(&base_file, &output_file, &options).unwrap();
+    commit_output(output_file, &temp_path, &output_path).unwrap();
+    assert!(!temp_path.exists());
+    assert_eq!(std::fs::read(&output_path).unwrap(), b"new output");
+    assert_eq!(std::fs::read(&user_path).unwrap(), b"user data");
+    let metadata = std::fs::metadata(&output_path).unwrap();
+    assert_eq!(metadata.modified().unwrap(), mtime);
+}
+
+#[test]
+fn test_apply_patch_regions() {
+    let size = 2 * APPLY_REGION_SIZE + 12345;
+    let a = make_test_text(40, size);
+    let mut b = a.clone();
+    for &offset in &[100, APPLY_REGION_SIZE - 3000, 2 * APPLY_REGION_SIZE - 10] {
+        b[offset..offset + 6000].copy_from_slice(&make_test_text(41, 6000));
+    }
+    b[APPLY_REGION_SIZE - 500..APPLY_REGION_SIZE + 700].fill(0xEE);
+    b.truncate(size - 100);
+    let patch_commands = compute_diff_trimmed(&a, &b, 1024, 1);
+    let patch = build_patch(&b, &patch_commands);
+    assert!(!patch.fill.is_empty());
+    let mut sequential: Vec<u8> = vec![0; patch.other_size as usize];
+    for cmd in &patch.base {
+        cmd.execute(&mut sequential, &a);
+    }
+    for cmd in &patch.other {
+        cmd.execute(&mut sequential, &patch.data);
+    }
+    for cmd in &patch.fill {
+        cmd.execute(&mut sequential);
+    }
+    let parallel = apply_patch(&a, &patch);
+    assert_eq!(compute_hash_strong(&sequential), compute_hash_strong(&b));
+    assert_eq!(compute_hash_strong(&parallel), compute_hash_strong(&b));
+    assert_eq!(compute_hash_strong_parallel(&b), compute_hash_strong(&b));
+}
+
+#[test]
+fn test_validate_patch() {
+    let a = make_test_text(50, 64 * 1024);
+    let mut b = a.clone();
+    b[5000..5100].copy_from_slice(&make_test_text(51, 100));
+    b[20000..22048].fill(0xAB);
+    let patch_commands = compute_diff_trimmed(&a, &b, 256, 1);
+    let patch = build_patch(&b, &patch_commands);
+    let header = make_test_header();
+    let codecs = PatchCodecs {
+        header: SectionCodec::new(Codec::Zstd, 3),
+        commands: SectionCodec::new(Codec::Zstd, 3),
+        data: SectionCodec::new(Codec::Zstd, 3),
+        data_chunk_size: None,
+    };
+    let (serialized, _) = serialize_patch(header, &patch, &codecs, None).unwrap();
+    let limits = PatchLimits::default();
+    let decode = || deserialize_patch(&serialized, None).unwrap();
+    let (header, valid) = decode();
+    let base_size = a.len() as u64;
+    assert_eq!(validate_patch(&header, &valid, base_size, &limits), Ok(()));
+    assert_eq!(
+        validate_patch(&header, &valid, base_size - 1000, &limits),
+        Err(PatchError::SourceOutOfBounds {
+            list: CommandList::Base,
+            index: valid.base.len() - 1
+        })
+    );
+
+    let (_, mut patch) = decode();
+    patch.other_size = u64::MAX;
+    assert!(matches!(
+        validate_patch(&header, &patch, base_size, &limits),
+        Err(PatchError::OutputTooLarge { .. })
+    ));
+    let in_memory_limits = PatchLimits::in_memory();
+    patch.other_size = in_memory_limits.max_output_size + 1;
+    assert!(matches!(
+        validate_patch(&header, &patch, base_size, &in_memory_limits),
+        Err(PatchError::OutputTooLarge { .. })
+    ));
+
+    let (_, mut patch) = decode();
+    patch.other[0].source = u64::MAX - 1;
+    assert_eq!(
+        validate_patch(&header, &patch, base_size, &limits),
+        Err(PatchError::SourceOutOfBounds {
+            list: CommandList::Other,
+            index: 0
+        })
+    );
+
+    let (_, mut patch) = decode();
+    patch.fill[0].target = patch.other_size;
+    assert_eq!(
+        validate_patch(&header, &patch, base_size, &limits),
+        Err(PatchError::TargetOutOfBounds {
+            list: CommandList::Fill,
+            index: 0
+        })
+    );
+
+    let (_, mut patch) = decode();
+    patch.base[0].target += 1;
+    assert_eq!(
+        validate_patch(&header, &patch, base_size, &limits),
+        Err(PatchError::UncoveredOutput { offset: 0 })
+    );
+
+    let (_, mut patch) = decode();
+    patch.other[0].size += 1;
+    assert!(matches!(
+        validate_patch(&header, &patch, base_size, &limits),
+        Err(PatchError::OverlappingTargets { .. })
+    ));
+
+    let mut masked_header = make_test_header();
+    masked_header.base_mask.push(MaskRange {
+        offset: base_size,
+        size: 1,
+    });
+    assert_eq!(
+        validate_patch(&masked_header, &valid, base_size, &limits),
+        Err(PatchError::MaskOutOfBounds { offset: base_size })
+    );
+
+    // BASE blocks come from the patch as well and must be non-empty, disjoint and in bounds
+    let blocks = compute_source_blocks(&a, &valid, 4096);
+    let mut blocks_header = make_test_header();
+    blocks_header.base_blocks = blocks.clone();
+    assert_eq!(
+        validate_patch(&blocks_header, &valid, base_size, &limits),
+        Ok(())
+    );
+    let last = blocks.len() - 1;
+    let invalid_blocks = [
+        // Empty
+        (0, blocks[0].offset, 0),
+        // Overlaps the previous block
+        (1, blocks[0].offset + 1, blocks[1].size),
+        // Offset overflows
+        (last, u64::MAX, 1),
+        // Past the end of BASE
+        (last, base_size - 100, 4096),
+    ];
+    for &(index, offset, size) in &invalid_blocks {
+        let mut blocks_header = make_test_header();
+        blocks_header.base_blocks = blocks.clone();
+        blocks_header.base_blocks[index].offset = offset;
+        blocks_header.base_blocks[index].size = size;
+        assert_eq!(
+            validate_patch(&blocks_header, &valid, base_size, &limits),
+            Err(PatchError::InvalidBaseBlock { offset })
+        );
+    }
+
+    let small_limits = PatchLimits {
+        max_section_size: 100,
+        ..limits
+    };
+    let error = match deserialize_patch_with_limits(&serialized, None, &small_limits) {
+        Ok(_) => panic!("Section size limit was not enforced"),
+        Err(e) => e,
+    };
+    let error = error
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<PatchError>()
+        .unwrap();
+    assert_eq!(
+        *error,
+        PatchError::SectionTooLarge {
+            section: "data",
+            limit: 100
+        }
+    );
+}
+
+#[test]
+fn test_apply_patch_to_writer() {
+    let size = STREAM_BATCH_REGIONS * APPLY_REGION_SIZE + 54321;
+    let a = make_test_text(60, size);
+    let mut b = a.clone();
+    b[1000..3000].copy_from_slice(&make_test_text(61, 2000));
+    b[size - 5000..size - 1000].fill(0);
+    let patch_commands = compute_diff_trimmed(&a, &b, 1024, 1);
+    let patch = build_patch(&b, &patch_commands);
+    // Masked region straddles the boundary between batches
+    let offset = STREAM_BATCH_REGIONS * APPLY_REGION_SIZE - 10;
+    let masked = vec![MaskedRegion {
+        offset: offset as u64,
+        data: vec![0x5A; 20],
+    }];
+    let mut expected = apply_patch(&a, &patch);
+    restore_masked_regions(&mut expected, &masked);
+    let mut output: Vec<u8> = Vec::new();
+    let hash = apply_patch_to_writer(&a, &patch, &masked, &mut output).unwrap();
+    assert_eq!(output.len(), expected.len());
+    assert!(output == expected);
+    assert_eq!(hash, compute_hash_strong(&expected));
+}
+
+#[test]
+fn test_diff_sparse() {
+    let size = 1 << 20;
+    let mut a = make_test_text(70, size);
+    let mut b = a.clone();
+    b[300000..301000].copy_from_slice(&make_test_text(71, 1000));
+    let a_holes = vec![Hole {
+        offset: 4096,
+        size: 65536,
+    }];
+    let b_holes = vec![
+        Hole {
+            offset: 0,
+            size: 8192,
+        },
+        Hole {
+            offset: 524288,
+            size: 131072,
+        },
+    ];
+    for hole in &a_holes {
+        a[hole.offset as usize..(hole.offset + hole.size) as usize].fill(0);
+    }
+    for hole in &b_holes {
+        b[hole.offset as usize..(hole.offset + hole.size) as usize].fill(0);
+    }
+    let params = DiffParams::new(1024, 1);
+    let (patch_commands, _) = compute_diff_sparse(&a, &a_holes, &b, &b_holes, &params);
+    let mut patch = build_patch(&b, &patch_commands);
+    add_hole_fills(&mut patch, &b_holes);
+    let mut header = make_test_header();
+    header.other_holes = b_holes.clone();
+    assert_eq!(
+        validate_patch(&header, &patch, a.len() as u64, &PatchLimits::default()),
+        Ok(())
+    );
+    let patched = apply_patch(&a, &patch);
+    assert_eq!(compute_hash_strong(&patched), compute_hash_strong(&b));
+    // Holes are never copied from BASE
+    for cmd in &patch.base {
+        for hole in &b_holes {
+            let end = cmd.target + cmd.size as u64;
+            assert!(end <= hole.offset || cmd.target >= hole.offset + hole.size);
+        }
+    }
+
+    let test_dir = TestDir::new("sparse");
+    let file = std::fs::File::create(test_dir.path().join("sparse")).unwrap();
+    file.set_len(size as u64).unwrap();
+    let holes = find_holes(&file).unwrap();
+    // File systems without SEEK_HOLE support report no holes
+    if !holes.is_empty() {
+        assert_eq!(
+            holes,
+            vec![Hole {
+                offset: 0,
+                size: size as u64,
+            }]
+        );
+    }
+}
+
+#[test]
+fn test_patch_range() {
+    let size = DATA_CHUNK_SIZE + (3 << 20);
+    let a: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let mut b = a.clone();
+    // Literal data spans two chunks
+    for (i, x) in b[1 << 20..size - (1 << 20)].iter_mut().enumerate() {
+        *x = (i * 7 % 241) as u8;
+    }
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.base = vec![
+        CopyCmd {
+            source: 0,
+            target: 0,
+            size: 1 << 20,
+        },
+        CopyCmd {
+            source: (size - (1 << 20)) as u64,
+            target: (size - (1 << 20)) as u64,
+            size: 1 << 20,
+        },
+    ];
+    patch_commands.other = vec![CopyCmd {
+        source: 1 << 20,
+        target: 1 << 20,
+        size: (size - (2 << 20)) as u32,
+    }];
+    let patch = build_patch(&b, &patch_commands);
+    assert!(patch.data.len() > DATA_CHUNK_SIZE);
+    let (begin, end) = (500000, 3000000);
+    let range = apply_patch_range(&a, &patch, begin, end);
+    assert!(range[..] == b[begin as usize..end as usize]);
+
+    let mut header = make_test_header();
+    header.other_regions = RegionHashes::new(&b, 1 << 20);
+    let codecs = PatchCodecs {
+        header: SectionCodec::new(Codec::None, 0),
+        commands: SectionCodec::new(Codec::None, 0),
+        data: SectionCodec::new(Codec::None, 0),
+        data_chunk_size: Some(DATA_CHUNK_SIZE),
+    };
+    let (serialized, _) = serialize_patch(header, &patch, &codecs, None).unwrap();
+    let (_, decoded) = deserialize_patch(&serialized, None).unwrap();
+    assert!(decoded.data == patch.data);
+
+    let limits = PatchLimits::default();
+    let (header, commands, literals) =
+        deserialize_patch_seekable(&serialized, None, &limits).unwrap();
+    assert!(commands.data.is_empty());
+    assert_eq!(literals.size(), patch.data.len() as u64);
+    assert_eq!(
+        validate_patch_commands(&header, &commands, literals.size(), a.len() as u64, &limits),
+        Ok(())
+    );
+    let (region_begin, region_end) =
+        header
+            .other_regions
+            .covering_range(begin, end, commands.other_size);
+    assert_eq!((region_begin, region_end), (0, 3 << 20));
+    let mut clipped = clip_patch_commands(&commands, region_begin, region_end);
+    literals.load_literals(&mut clipped).unwrap();
+    // Only the first chunk is decompressed
+    assert_eq!(clipped.data.len(), DATA_CHUNK_SIZE);
+    let mut result = apply_patch(&a, &clipped);
+    assert_eq!(header.other_regions.verify(&result, region_begin), Ok(()));
+    assert!(result[begin as usize..end as usize] == b[begin as usize..end as usize]);
+    result[2 << 20] ^= 1;
+    assert_eq!(
+        header.other_regions.verify(&result, region_begin),
+        Err(2 << 20)
+    );
+
+    // Literal data of patches that are not seekable is a single chunk
+    let codecs = PatchCodecs {
+        data_chunk_size: None,
+        ..codecs
+    };
+    let (serialized, _) = serialize_patch(make_test_header(), &patch, &codecs, None).unwrap();
+    let (_, commands, literals) = deserialize_patch_seekable(&serialized, None, &limits).unwrap();
+    let mut clipped = clip_patch_commands(&commands, region_begin, region_end);
+    literals.load_literals(&mut clipped).unwrap();
+    assert!(clipped.data == patch.data);
+    let mut clipped = clip_patch_commands(&commands, region_begin, region_end);
+    clipped.other[0].source = u64::MAX;
+    let error = literals.load_literals(&mut clipped).unwrap_err();
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<PatchError>(),
+        Some(&PatchError::SourceOutOfBounds {
+            list: CommandList::Other,
+            index: 0
+        })
+    );
+}
+
+#[test]
+fn test_relocate_patch() {
+    let a = make_test_text(80, 1 << 20);
+    let mut b = a.clone();
+    b[300000..301000].copy_from_slice(&make_test_text(81, 1000));
+    let block_size = 1024;
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    let patch = build_patch(&b, &patch_commands);
+    let blocks = compute_source_blocks(&a, &patch, 3000);
+    // Last block is shorter, so that blocks are disjoint
+    let last = &blocks[blocks.len() - 2..];
+    assert_eq!(last[1].offset, last[0].offset + last[0].size as u64);
+    assert!(last[1].size < last[0].size);
+
+    // Inserted data splits blocks, whose parts are found at neighbor shifts
+    let mut c = a[..100000].to_vec();
+    c.extend_from_slice(&make_test_text(82, 500));
+    c.extend_from_slice(&a[100000..700003]);
+    c.extend_from_slice(&make_test_text(83, 123));
+    c.extend_from_slice(&a[700003..]);
+    let mut relocated = build_patch(&b, &patch_commands);
+    let stats = relocate_patch(&c, &mut relocated, &blocks).unwrap();
+    assert!(stats.intact > 0 && stats.relocated > 0);
+    assert_eq!(
+        validate_patch(
+            &make_test_header(),
+            &relocated,
+            c.len() as u64,
+            &PatchLimits::default()
+        ),
+        Ok(())
+    );
+    let patched = apply_patch(&c, &relocated);
+    assert_eq!(compute_hash_strong(&patched), compute_hash_strong(&b));
+
+    // Removed data that the patch copies can't be recovered
+    let mut d = a[..500000].to_vec();
+    d.extend_from_slice(&a[500100..]);
+    let mut relocated = build_patch(&b, &patch_commands);
+    assert!(matches!(
+        relocate_patch(&d, &mut relocated, &blocks),
+        Err(PatchError::MissingBaseBlock { .. })
+    ));
+}
@@ -0,0 +1,304 @@
+use crate::container::*;
+use crate::patchy::*;
+use crate::precomp::*;
+use core::fmt;
+
+// Limits that protect patch reading and application from malformed or malicious patches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PatchLimits {
+    // Largest output file that the patch may produce
+    pub max_output_size: u64,
+    // Largest decompressed size of each patch file section
+    pub max_section_size: u64,
+}
+
+impl Default for PatchLimits {
+    fn default() -> Self {
+        Self {
+            max_output_size: 1 << 40,
+            max_section_size: 1 << 36,
+        }
+    }
+}
+
+impl PatchLimits {
+    // Limits for patches whose output is built in memory, which must not allocate as much as an
+    // output that is written directly to a file
+    pub fn in_memory() -> Self {
+        Self {
+            max_output_size: 1 << 34,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandList {
+    Base,
+    Other,
+    Fill,
+}
+
+impl CommandList {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandList::Base => "BASE copy",
+            CommandList::Other => "literal copy",
+            CommandList::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    OutputTooLarge { size: u64, limit: u64 },
+    SectionTooLarge { section: &'static str, limit: u64 },
+    // Literal data is larger than the output it is copied to
+    DataTooLarge { size: u64, output_size: u64 },
+    SourceOutOfBounds { list: CommandList, index: usize },
+    TargetOutOfBounds { list: CommandList, index: usize },
+    OverlappingTargets { offset: u64 },
+    UncoveredOutput { offset: u64 },
+    MaskOutOfBounds { offset: u64 },
+    StreamOutOfBounds { offset: u64 },
+    HoleOutOfBounds { offset: u64 },
+    // Region hashes don't match output file size
+    InvalidRegionHashes,
+    // Modified BASE doesn't contain data that the patch copies
+    MissingBaseBlock { offset: u64 },
+    // BASE block hash is empty, out of bounds or not after the previous one
+    InvalidBaseBlock { offset: u64 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::OutputTooLarge { size, limit } => write!(
+                f,
+                "Patch output size {} exceeds the limit of {} bytes",
+                size, limit
+            ),
+            PatchError::SectionTooLarge { section, limit } => write!(
+                f,
+                "Patch section '{}' decompresses to more than {} bytes",
+                section, limit
+            ),
+            PatchError::DataTooLarge { size, output_size } => write!(
+                f,
+                "Patch data size {} is larger than output size {}",
+                size, output_size
+            ),
+            PatchError::SourceOutOfBounds { list, index } => write!(
+                f,
+                "Source of {} command {} is out of bounds",
+                list.name(),
+                index
+            ),
+            PatchError::TargetOutOfBounds { list, index } => write!(
+                f,
+                "Target of {} command {} is out of bounds",
+                list.name(),
+                index
+            ),
+            PatchError::OverlappingTargets { offset } => {
+                write!(f, "Patch commands overlap at output offset {}", offset)
+            }
+            PatchError::UncoveredOutput { offset } => write!(
+                f,
+                "Output at offset {} is not covered by patch commands",
+                offset
+            ),
+            PatchError::MaskOutOfBounds { offset } => {
+                write!(f, "Masked region at offset {} is out of bounds", offset)
+            }
+            PatchError::StreamOutOfBounds { offset } => {
+                write!(f, "Embedded stream at offset {} is out of bounds", offset)
+            }
+            PatchError::HoleOutOfBounds { offset } => {
+                write!(f, "Hole at offset {} is out of bounds", offset)
+            }
+            PatchError::InvalidRegionHashes => {
+                write!(f, "Region hashes don't match output file size")
+            }
+            PatchError::MissingBaseBlock { offset } => write!(
+                f,
+                "BASE block at offset {} is modified and was not found elsewhere",
+                offset
+            ),
+            PatchError::InvalidBaseBlock { offset } => {
+                write!(f, "BASE block at offset {} is invalid", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<PatchError> for std::io::Error {
+    fn from(e: PatchError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn range_end(offset: u64, size: u64, bound: u64) -> Option<u64> {
+    offset.checked_add(size).filter(|&end| end <= bound)
+}
+
+// Returns size of data with embedded streams expanded. Streams must be sorted and fit into
+// data with compressed streams, which is what their offsets refer to.
+fn expanded_stream_size(size: u64, streams: &[CompressedStream]) -> Result<u64, PatchError> {
+    let mut pos: u64 = 0;
+    let mut result: u64 = size;
+    for stream in streams {
+        let out_of_bounds = PatchError::StreamOutOfBounds {
+            offset: stream.offset,
+        };
+        if stream.offset < pos {
+            return Err(out_of_bounds);
+        }
+        pos = range_end(stream.offset, stream.compressed_size, size).ok_or(out_of_bounds)?;
+        result = (result - stream.compressed_size)
+            .checked_add(stream.decompressed_size)
+            .ok_or(out_of_bounds)?;
+    }
+    Ok(result)
+}
+
+// Checks that the patch can be applied to BASE of the given size (before any transforms) without
+// running out of bounds or exceeding limits. Commands must write every output byte exactly once.
+// Patches from untrusted sources must be validated before apply_patch, which assumes this.
+pub fn validate_patch(
+    header: &PatchHeader,
+    patch: &Patch,
+    base_size: u64,
+    limits: &PatchLimits,
+) -> Result<(), PatchError> {
+    validate_patch_commands(header, patch, patch.data.len() as u64, base_size, limits)
+}
+
+// Same as validate_patch for a patch whose literal data of `data_size` bytes is not loaded
+pub fn validate_patch_commands(
+    header: &PatchHeader,
+    patch: &Patch,
+    data_size: u64,
+    base_size: u64,
+    limits: &PatchLimits,
+) -> Result<(), PatchError> {
+    let output_size = patch.other_size;
+    if output_size > limits.max_output_size {
+        return Err(PatchError::OutputTooLarge {
+            size: output_size,
+            limit: limits.max_output_size,
+        });
+    }
+    if data_size > output_size {
+        return Err(PatchError::DataTooLarge {
+            size: data_size,
+            output_size,
+        });
+    }
+
+    // BASE is masked before its embedded streams are expanded
+    for range in &header.base_mask {
+        if range_end(range.offset, range.size, base_size).is_none() {
+            return Err(PatchError::MaskOutOfBounds {
+                offset: range.offset,
+            });
+        }
+    }
+    // Blocks are sorted and disjoint, like compute_source_blocks makes them
+    let mut blocks_end: u64 = 0;
+    for block in &header.base_blocks {
+        blocks_end = range_end(block.offset, block.size as u64, base_size)
+            .filter(|_| block.size != 0 && block.offset >= blocks_end)
+            .ok_or(PatchError::InvalidBaseBlock {
+                offset: block.offset,
+            })?;
+    }
+    let base_size = expanded_stream_size(base_size, &header.base_streams)?;
+    // Output file size after embedded streams are recompressed
+    let file_size = header
+        .other_streams
+        .iter()
+        .try_fold(output_size, |size, stream| {
+            size.checked_sub(stream.decompressed_size)?
+                .checked_add(stream.compressed_size)
+        })
+        .filter(|&file_size| {
+            expanded_stream_size(file_size, &header.other_streams) == Ok(output_size)
+        })
+        .ok_or(PatchError::StreamOutOfBounds { offset: 0 })?;
+    if file_size > limits.max_output_size {
+        return Err(PatchError::OutputTooLarge {
+            size: file_size,
+            limit: limits.max_output_size,
+        });
+    }
+    for region in &header.other_masked {
+        if range_end(region.offset, region.data.len() as u64, file_size).is_none() {
+            return Err(PatchError::MaskOutOfBounds {
+                offset: region.offset,
+            });
+        }
+    }
+
+    for hole in &header.other_holes {
+        if range_end(hole.offset, hole.size, file_size).is_none() {
+            return Err(PatchError::HoleOutOfBounds {
+                offset: hole.offset,
+            });
+        }
+    }
+
+    let regions = &header.other_regions;
+    if !regions.is_empty()
+        && (regions.region_size == 0
+            || regions.hashes.len() as u64 != file_size.div_ceil(regions.region_size))
+    {
+        return Err(PatchError::InvalidRegionHashes);
+    }
+
+    // Target ranges of all commands, which must tile the output
+    let mut targets: Vec<(u64, u64)> =
+        Vec::with_capacity(patch.base.len() + patch.other.len() + patch.fill.len());
+    let copy_lists = [
+        (CommandList::Base, &patch.base, base_size),
+        (CommandList::Other, &patch.other, data_size),
+    ];
+    for (list, cmds, source_size) in copy_lists.iter() {
+        for (index, cmd) in cmds.iter().enumerate() {
+            let (list, index) = (*list, index);
+            if range_end(cmd.source, cmd.size as u64, *source_size).is_none() {
+                return Err(PatchError::SourceOutOfBounds { list, index });
+            }
+            let end = range_end(cmd.target, cmd.size as u64, output_size)
+                .ok_or(PatchError::TargetOutOfBounds { list, index })?;
+            targets.push((cmd.target, end));
+        }
+    }
+    for (index, cmd) in patch.fill.iter().enumerate() {
+        let end = range_end(cmd.target, cmd.size as u64, output_size).ok_or(
+            PatchError::TargetOutOfBounds {
+                list: CommandList::Fill,
+                index,
+            },
+        )?;
+        targets.push((cmd.target, end));
+    }
+    targets.retain(|(begin, end)| begin != end);
+    targets.sort_unstable();
+    let mut covered: u64 = 0;
+    for (begin, end) in targets {
+        if begin < covered {
+            return Err(PatchError::OverlappingTargets { offset: begin });
+        }
+        if begin > covered {
+            return Err(PatchError::UncoveredOutput { offset: covered });
+        }
+        covered = end;
+    }
+    if covered != output_size {
+        return Err(PatchError::UncoveredOutput { offset: covered });
+    }
+    Ok(())
+}